@@ -1,7 +1,6 @@
 extern crate hyper;
 extern crate pony;
 extern crate futures;
-use hyper::server::{Http, NewService};
 use pony::pony_builder::PonyBuilder;
 
 mod routes;
@@ -13,6 +12,5 @@ fn main() {
     pb.put("/put", routes::put);
     pb.post("/post", routes::post);
     pb.delete("/delete", routes::delete);
-    let handler = Http::new().bind(&addr, move || pb.new_service()).unwrap();
-    handler.run().unwrap();
+    pb.serve(&addr).unwrap();
 }
\ No newline at end of file
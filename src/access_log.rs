@@ -0,0 +1,397 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{Async, Poll, Stream};
+
+use hyper::{Body, Chunk, Error, Method, StatusCode};
+use hyper::server::{Request, Response};
+use hyper::header::{ContentLength, Headers};
+
+use super::escape::json_string;
+
+///The layout of each access log line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    ///`host - - [time] "request" status bytes`
+    Common,
+    ///Common followed by `"referrer" "user agent"`
+    Combined,
+    ///One json object per line, including the duration
+    Json,
+}
+
+///Where access log lines are written
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogTarget {
+    Stdout,
+    ///Append to a file, once it would grow past `max_bytes` it
+    /// is renamed to `<path>.1`, any older files are shifted up
+    /// and only `keep` old files are kept
+    File { path: PathBuf, max_bytes: u64, keep: usize },
+}
+
+///Everything recorded about one request
+#[derive(Clone, Debug)]
+pub struct LogEntry {
+    pub remote_addr: Option<SocketAddr>,
+    pub method: Method,
+    ///The path and query of the request
+    pub path: String,
+    pub version: String,
+    pub status: StatusCode,
+    ///The bytes of the response body that were sent, or its
+    /// Content-Length when a `Pony` is called directly
+    pub bytes: Option<u64>,
+    pub duration: Duration,
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    pub time: SystemTime,
+    pub request_id: Option<String>,
+}
+
+impl LogEntry {
+    ///Start an entry for a request, the status is a 500
+    /// until the response is known
+    pub fn new(req: &Request) -> LogEntry {
+        //hyper only knows the remote address when it was
+        //accepted through `Http::bind`, `Pony` fills it in
+        //for connections accepted by `PonyBuilder::serve`
+        #[allow(deprecated)]
+        let remote_addr = req.remote_addr();
+        LogEntry {
+            remote_addr,
+            method: req.method().clone(),
+            path: match req.query() {
+                Some(query) => format!("{}?{}", req.path(), query),
+                None => req.path().to_string(),
+            },
+            version: req.version().to_string(),
+            status: StatusCode::InternalServerError,
+            bytes: None,
+            duration: Duration::default(),
+            referrer: header_string(req.headers(), "Referer"),
+            user_agent: header_string(req.headers(), "User-Agent"),
+            time: SystemTime::now(),
+            request_id: None,
+        }
+    }
+    ///Render this entry as a single line without a trailing newline,
+    /// Common and Combined lines end with the request id if there is one
+    pub fn format(&self, format: LogFormat) -> String {
+        let line = match format {
+            LogFormat::Common => self.common(),
+            LogFormat::Combined => format!("{} \"{}\" \"{}\"",
+                                           self.common(),
+                                           quoted(self.referrer.as_deref().unwrap_or("-")),
+                                           quoted(self.user_agent.as_deref().unwrap_or("-"))),
+            LogFormat::Json => return self.json(),
+        };
+        match self.request_id {
+            Some(ref id) => format!("{} {}", line, id),
+            None => line,
+        }
+    }
+
+    fn common(&self) -> String {
+        format!("{} - - [{}] \"{} {} {}\" {} {}",
+                self.remote_addr.map(|a| a.ip().to_string()).unwrap_or_else(|| String::from("-")),
+                clf_time(self.time),
+                self.method,
+                quoted(&self.path),
+                self.version,
+                u16::from(self.status),
+                self.bytes.map(|b| b.to_string()).unwrap_or_else(|| String::from("-")))
+    }
+
+    fn json(&self) -> String {
+        let opt = |s: &Option<String>| s.as_ref().map(|s| json_string(s)).unwrap_or_else(|| String::from("null"));
+        format!("{{\"time\": {}, \"remote_addr\": {}, \"method\": {}, \"path\": {}, \"version\": {}, \"status\": {}, \"bytes\": {}, \"duration_ms\": {:.3}, \"referrer\": {}, \"user_agent\": {}, \"request_id\": {}}}",
+                json_string(&rfc3339(self.time)),
+                opt(&self.remote_addr.map(|a| a.ip().to_string())),
+                json_string(self.method.as_ref()),
+                json_string(&self.path),
+                json_string(&self.version),
+                u16::from(self.status),
+                self.bytes.map(|b| b.to_string()).unwrap_or_else(|| String::from("null")),
+                self.duration.as_secs() as f64 * 1000.0 + self.duration.subsec_nanos() as f64 / 1_000_000.0,
+                opt(&self.referrer),
+                opt(&self.user_agent),
+                opt(&self.request_id))
+    }
+}
+
+enum Output {
+    Stdout,
+    File { path: PathBuf, file: File, len: u64, max_bytes: u64, keep: usize },
+}
+
+///Writes a line for every request, shared by every `Pony`
+/// a builder creates
+pub struct AccessLog {
+    format: LogFormat,
+    output: Mutex<Output>,
+}
+
+impl AccessLog {
+    ///Open the log's target, files are created if missing
+    /// and appended to otherwise
+    pub fn new(format: LogFormat, target: LogTarget) -> io::Result<AccessLog> {
+        let output = match target {
+            LogTarget::Stdout => Output::Stdout,
+            LogTarget::File { path, max_bytes, keep } => {
+                let file = open_append(&path)?;
+                let len = file.metadata()?.len();
+                Output::File { path, file, len, max_bytes, keep }
+            },
+        };
+        Ok(AccessLog {
+            format,
+            output: Mutex::new(output),
+        })
+    }
+    ///Write an entry, failures to write are ignored
+    /// so logging can never fail a request
+    pub fn log(&self, entry: &LogEntry) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        if let Ok(mut output) = self.output.lock() {
+            let _ = output.write_line(&line);
+        }
+    }
+}
+
+///An entry waiting for its response to be sent
+/// and the log to write it to
+pub type PendingLog = (Arc<AccessLog>, LogEntry);
+
+///Log an entry straight away with the response's Content-Length
+pub fn log_now(log: &AccessLog, entry: LogEntry, res: &Response) {
+    let mut entry = entry;
+    entry.status = res.status();
+    entry.bytes = res.headers().get::<ContentLength>().map(|l| l.0);
+    log.log(&entry);
+}
+
+///Log an entry once the response's body has been sent, or
+/// the client went away, with the bytes read from it.
+/// `sent` runs at the same time, even without a log
+pub fn log_sent(log: Option<PendingLog>, res: Response, sent: Box<dyn FnOnce()>) -> Response<SentBody> {
+    let mut res = res;
+    let log = log.map(|(log, mut entry)| {
+        entry.status = res.status();
+        (log, entry)
+    });
+    let headers = mem::replace(res.headers_mut(), Headers::new());
+    let response = Response::new().with_status(res.status()).with_headers(headers);
+    let mut body = SentBody { body: Body::empty(), sent: 0, log, on_sent: Some(sent) };
+    if res.body_ref().is_none() {
+        body.finish();
+        return response
+    }
+    body.body = res.body();
+    response.with_body(body)
+}
+
+///A response body that counts the bytes read from it
+pub struct SentBody {
+    body: Body,
+    sent: u64,
+    log: Option<PendingLog>,
+    on_sent: Option<Box<dyn FnOnce()>>,
+}
+
+impl SentBody {
+    fn finish(&mut self) {
+        if let Some((log, mut entry)) = self.log.take() {
+            entry.bytes = Some(self.sent);
+            log.log(&entry);
+        }
+        if let Some(on_sent) = self.on_sent.take() {
+            on_sent();
+        }
+    }
+}
+
+impl Stream for SentBody {
+    type Item = Chunk;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Chunk>, Error> {
+        match self.body.poll() {
+            Ok(Async::Ready(Some(chunk))) => {
+                self.sent += chunk.len() as u64;
+                Ok(Async::Ready(Some(chunk)))
+            },
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            done => {
+                self.finish();
+                done
+            },
+        }
+    }
+}
+
+impl Drop for SentBody {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl Output {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match *self {
+            Output::Stdout => {
+                let stdout = io::stdout();
+                let mut lock = stdout.lock();
+                lock.write_all(line.as_bytes())
+            },
+            Output::File { ref path, ref mut file, ref mut len, max_bytes, keep } => {
+                if *len > 0 && *len + line.len() as u64 > max_bytes {
+                    rotate(path, keep)?;
+                    *file = open_append(path)?;
+                    *len = 0;
+                }
+                file.write_all(line.as_bytes())?;
+                *len += line.len() as u64;
+                Ok(())
+            },
+        }
+    }
+}
+
+///The first value of a request header as a string
+fn header_string(headers: &Headers, name: &str) -> Option<String> {
+    headers.get_raw(name)
+        .and_then(|raw| raw.one())
+        .map(|line| String::from_utf8_lossy(line).to_string())
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+///`<path>.n` for a rotated log file
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+///Shift every old log up by one, dropping the oldest
+/// and move the current log to `<path>.1`
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    if keep == 0 {
+        return fs::remove_file(path)
+    }
+    let oldest = rotated(path, keep);
+    if oldest.exists() {
+        fs::remove_file(&oldest)?;
+    }
+    for n in (1..keep).rev() {
+        let from = rotated(path, n);
+        if from.exists() {
+            fs::rename(&from, rotated(path, n + 1))?;
+        }
+    }
+    fs::rename(path, rotated(path, 1))
+}
+
+///Escape `"` and `\` for a quoted field
+fn quoted(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+///The year, month and day for a number of days since the unix epoch,
+/// see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+///The date and time parts of a timestamp in UTC
+fn utc_parts(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+///`10/Oct/2000:13:55:36 +0000`
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day, hour, minute, second) = utc_parts(time);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000", day, MONTHS[month as usize - 1], year, hour, minute, second)
+}
+
+///`2000-10-10T13:55:36Z`
+fn rfc3339(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc_parts(time);
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn entry() -> LogEntry {
+        LogEntry {
+            remote_addr: Some("127.0.0.1:5000".parse().unwrap()),
+            method: Method::Get,
+            path: String::from("/apache_pb.gif?a=1"),
+            version: String::from("HTTP/1.1"),
+            status: StatusCode::Ok,
+            bytes: Some(2326),
+            duration: Duration::from_micros(1500),
+            referrer: Some(String::from("http://www.example.com/start.html")),
+            user_agent: None,
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn formats() {
+        let entry = entry();
+        assert_eq!(entry.format(LogFormat::Common), "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif?a=1 HTTP/1.1\" 200 2326");
+        assert_eq!(entry.format(LogFormat::Combined), "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif?a=1 HTTP/1.1\" 200 2326 \"http://www.example.com/start.html\" \"-\"");
+        assert_eq!(entry.format(LogFormat::Json), "{\"time\": \"2000-10-10T13:55:36Z\", \"remote_addr\": \"127.0.0.1\", \"method\": \"GET\", \"path\": \"/apache_pb.gif?a=1\", \"version\": \"HTTP/1.1\", \"status\": 200, \"bytes\": 2326, \"duration_ms\": 1.500, \"referrer\": \"http://www.example.com/start.html\", \"user_agent\": null, \"request_id\": null}");
+        let entry = LogEntry { request_id: Some(String::from("abc")), ..entry };
+        assert!(entry.format(LogFormat::Common).ends_with(" 200 2326 abc"));
+        assert!(entry.format(LogFormat::Json).ends_with("\"request_id\": \"abc\"}"));
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn rotation() {
+        let path = ::test_support::temp_path("access.log");
+        let log = AccessLog::new(LogFormat::Common, LogTarget::File { path: path.clone(), max_bytes: 150, keep: 1 }).unwrap();
+        for _ in 0..3 {
+            log.log(&entry());
+        }
+        let mut current = String::new();
+        File::open(&path).unwrap().read_to_string(&mut current).unwrap();
+        assert_eq!(current.lines().count(), 1);
+        assert!(rotated(&path, 1).exists());
+        assert!(!rotated(&path, 2).exists());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(rotated(&path, 1)).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tar;
+use zip::ZipArchive;
+use zip::DateTime;
+
+use super::autoindex::Entry;
+use super::file_cache::StaticFile;
+use super::source::{flat_is_dir, flat_list, Metadata, ReadContext, StaticSource};
+
+enum Kind {
+    Zip(Mutex<ZipArchive<File>>),
+    Tar,
+}
+
+struct ArchiveEntry {
+    len: u64,
+    modified: Option<SystemTime>,
+    ///The index of a zip entry or the offset of a
+    /// tar entry's contents in the archive
+    location: u64,
+}
+
+///A `.zip` or `.tar` file served without extracting it.
+/// Every file in the archive is indexed when the source is
+/// opened and read from the archive on each request, an
+/// entry named `<file>.gz` is used as the gzip encoded
+/// version of `<file>`
+pub struct ArchiveSource {
+    path: PathBuf,
+    kind: Kind,
+    entries: BTreeMap<String, ArchiveEntry>,
+}
+
+impl ArchiveSource {
+    ///Open and index an archive, the format is chosen
+    /// by the file's extension
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ArchiveSource> {
+        let path = path.as_ref().to_path_buf();
+        let ext = path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match ext.as_deref() {
+            Some("zip") => Self::open_zip(path),
+            Some("tar") => Self::open_tar(path),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Archive must be a .zip or .tar file\n{:?}", &path))),
+        }
+    }
+    ///The archive this source serves
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn open_zip(path: PathBuf) -> io::Result<ArchiveSource> {
+        let mut zip = ZipArchive::new(File::open(&path)?).map_err(invalid_data)?;
+        let mut entries = BTreeMap::new();
+        for idx in 0..zip.len() {
+            let file = zip.by_index(idx).map_err(invalid_data)?;
+            if file.is_dir() {
+                continue;
+            }
+            if let Some(name) = normalize(file.name()) {
+                entries.insert(name, ArchiveEntry {
+                    len: file.size(),
+                    modified: zip_time(file.last_modified()),
+                    location: idx as u64,
+                });
+            }
+        }
+        Ok(ArchiveSource {
+            path,
+            kind: Kind::Zip(Mutex::new(zip)),
+            entries,
+        })
+    }
+
+    fn open_tar(path: PathBuf) -> io::Result<ArchiveSource> {
+        let mut tar = tar::Archive::new(File::open(&path)?);
+        let mut entries = BTreeMap::new();
+        for entry in tar.entries()? {
+            let entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry.path()?.to_string_lossy().to_string();
+            if let Some(name) = normalize(&name) {
+                entries.insert(name, ArchiveEntry {
+                    len: entry.size(),
+                    modified: entry.header().mtime().ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                    location: entry.raw_file_position(),
+                });
+            }
+        }
+        Ok(ArchiveSource {
+            path,
+            kind: Kind::Tar,
+            entries,
+        })
+    }
+
+    fn read(&self, entry: &ArchiveEntry) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::with_capacity(entry.len as usize);
+        match self.kind {
+            Kind::Zip(ref zip) => {
+                let mut zip = zip.lock().map_err(|_| io::Error::other("zip archive lock poisoned"))?;
+                zip.by_index(entry.location as usize)
+                    .map_err(invalid_data)?
+                    .read_to_end(&mut contents)?;
+            },
+            Kind::Tar => {
+                let mut file = File::open(&self.path)?;
+                file.seek(SeekFrom::Start(entry.location))?;
+                file.take(entry.len).read_to_end(&mut contents)?;
+            },
+        }
+        Ok(contents)
+    }
+
+    fn flat_files(&self) -> impl Iterator<Item = (&str, u64, Option<SystemTime>)> {
+        self.entries.iter().map(|(path, entry)| (path.as_str(), entry.len, entry.modified))
+    }
+}
+
+impl StaticSource for ArchiveSource {
+    fn open(&self, path: &str, gzipped: bool, cx: &ReadContext) -> io::Result<StaticFile> {
+        let name = if gzipped {
+            format!("{}.gz", path)
+        } else {
+            path.to_string()
+        };
+        let entry = self.entries.get(&name).ok_or_else(not_found)?;
+        let contents = self.read(entry)?;
+        Ok(StaticFile::new(contents, Path::new(&name), gzipped, cx.etag, entry.modified))
+    }
+    fn metadata(&self, path: &str, _cx: &ReadContext) -> io::Result<Metadata> {
+        if let Some(entry) = self.entries.get(path) {
+            return Ok(Metadata {
+                is_dir: false,
+                len: entry.len,
+                modified: entry.modified,
+            })
+        }
+        if flat_is_dir(self.flat_files(), path) {
+            Ok(Metadata { is_dir: true, len: 0, modified: None })
+        } else {
+            Err(not_found())
+        }
+    }
+    fn list(&self, path: &str, _cx: &ReadContext) -> io::Result<Vec<Entry>> {
+        Ok(flat_list(self.flat_files(), path))
+    }
+    fn verify(&self) -> Result<(), String> {
+        if !self.path.is_file() {
+            return Err(format!("Archive path does not exist\n{:?}", &self.path));
+        }
+        Ok(())
+    }
+}
+
+///Strip any leading `./` or `/` from an entry name, entries
+/// that would escape the archive's root are dropped
+fn normalize(name: &str) -> Option<String> {
+    let segments: Vec<&str> = name.split(['/', '\\'])
+        .filter(|s| !s.is_empty() && *s != ".")
+        .collect();
+    if segments.is_empty() || segments.contains(&"..") {
+        return None
+    }
+    Some(segments.join("/"))
+}
+
+///Convert the MS-DOS date and time stored in a zip
+/// entry, these have no time zone so UTC is assumed
+fn zip_time(dt: DateTime) -> Option<SystemTime> {
+    let (year, month, day) = (dt.year() as i64, dt.month() as i64, dt.day() as i64);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None
+    }
+    //days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + dt.hour() as i64 * 3600 + dt.minute() as i64 * 60 + dt.second() as i64;
+    if secs < 0 {
+        return None
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+fn invalid_data(e: ::zip::result::ZipError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "File not found")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use super::super::pony::{ETag, SymlinkPolicy};
+    use super::super::test_support::{tar_file, zip_file};
+
+    fn cx() -> ReadContext<'static> {
+        ReadContext {
+            etag: ETag::LastModified,
+            symlinks: SymlinkPolicy::WithinRoot,
+            cache: None,
+        }
+    }
+
+    fn check(source: &ArchiveSource) {
+        assert!(source.verify().is_ok());
+        let file = source.open("index.html", false, &cx()).unwrap();
+        assert_eq!(&file.contents[..], b"<h1>archive</h1>");
+        assert_eq!(file.content_type, "text/html; charset=utf-8");
+        assert!(file.etag.is_some());
+        let gz = source.open("index.html", true, &cx()).unwrap();
+        assert_eq!(&gz.contents[..], b"gzipped");
+        assert_eq!(gz.content_type, "text/html; charset=utf-8");
+        assert_eq!(source.open("css/site.css", true, &cx()).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(&source.open("js/app/main.js", false, &cx()).unwrap().contents[..], b"main()");
+        assert!(source.metadata("js/app", &cx()).unwrap().is_dir);
+        assert!(source.metadata("", &cx()).unwrap().is_dir);
+        assert_eq!(source.metadata("css/site.css", &cx()).unwrap().len, 7);
+        assert!(source.metadata("missing", &cx()).is_err());
+        let names: Vec<String> = source.list("", &cx()).unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["css", "js", "index.html", "index.html.gz"]);
+    }
+
+    #[test]
+    fn zip() {
+        let path = zip_file("site.zip");
+        let source = ArchiveSource::open(&path).unwrap();
+        check(&source);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tar() {
+        let path = tar_file("site.tar");
+        let source = ArchiveSource::open(&path).unwrap();
+        check(&source);
+        let modified = source.metadata("index.html", &cx()).unwrap().modified;
+        assert_eq!(modified, Some(UNIX_EPOCH + Duration::from_secs(1_500_000_000)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unsupported() {
+        assert_eq!(ArchiveSource::open("examples/public/index.html").err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        assert!(ArchiveSource::open("junk.zip").is_err());
+    }
+
+    #[test]
+    fn normalized() {
+        assert_eq!(normalize("./a//b.txt"), Some(String::from("a/b.txt")));
+        assert_eq!(normalize("/a\\b.txt"), Some(String::from("a/b.txt")));
+        assert_eq!(normalize("../b.txt"), None);
+        assert_eq!(normalize("./"), None);
+    }
+
+    #[test]
+    fn zip_times() {
+        let dt = DateTime::from_date_and_time(2017, 7, 14, 2, 40, 0).unwrap();
+        assert_eq!(zip_time(dt), Some(UNIX_EPOCH + Duration::from_secs(1_500_000_000)));
+    }
+}
@@ -0,0 +1,209 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::HttpDate;
+
+use percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use super::escape::{html_escape, json_string};
+
+///A single item in a directory listing
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+///Read the contents of a directory on disk
+/// the result will be sorted with directories first
+/// and then by name
+pub fn read_entries(dir: &Path, hide_dotfiles: bool) -> io::Result<Vec<Entry>> {
+    let mut entries = vec!();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if hide_dotfiles && name.starts_with('.') {
+            continue;
+        }
+        let md = entry.metadata()?;
+        entries.push(Entry {
+            name,
+            is_dir: md.is_dir(),
+            size: md.len(),
+            modified: md.modified().ok(),
+        });
+    }
+    sort_entries(&mut entries);
+    Ok(entries)
+}
+
+///Sort a listing with directories first and then by name
+pub fn sort_entries(entries: &mut [Entry]) {
+    entries.sort_by(|lhs, rhs| rhs.is_dir.cmp(&lhs.is_dir).then_with(|| lhs.name.cmp(&rhs.name)));
+}
+
+///Render a listing as an html page
+/// url_path is the decoded request path for the directory
+pub fn render_html(url_path: &str, entries: &[Entry]) -> String {
+    let base = with_trailing_slash(url_path);
+    let title = html_escape(&format!("Index of {}", base));
+    let mut rows = String::new();
+    if let Some(parent) = parent(&base) {
+        rows += &format!("<tr><td><a href=\"{}\">../</a></td><td>-</td><td>-</td></tr>\n", html_escape(&encode_path(&parent)));
+    }
+    for entry in entries {
+        let display = if entry.is_dir {
+            format!("{}/", entry.name)
+        } else {
+            entry.name.clone()
+        };
+        let size = if entry.is_dir {
+            String::from("-")
+        } else {
+            entry.size.to_string()
+        };
+        let modified = match entry.modified {
+            Some(t) => HttpDate::from(t).to_string(),
+            None => String::from("-"),
+        };
+        rows += &format!("<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                        html_escape(&href(&base, entry)),
+                        html_escape(&display),
+                        size,
+                        modified);
+    }
+    format!("<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>{title}</title>
+</head>
+<body>
+<h1>{title}</h1>
+<table>
+<tr><th>Name</th><th>Size</th><th>Last Modified</th></tr>
+{rows}</table>
+</body>
+</html>
+", title = title, rows = rows)
+}
+
+///Render a listing as a json document
+/// url_path is the decoded request path for the directory
+pub fn render_json(url_path: &str, entries: &[Entry]) -> String {
+    let base = with_trailing_slash(url_path);
+    let parent = match parent(&base) {
+        Some(p) => json_string(&encode_path(&p)),
+        None => String::from("null"),
+    };
+    let items: Vec<String> = entries.iter().map(|entry| {
+        let modified = match entry.modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+            Some(d) => d.as_secs().to_string(),
+            None => String::from("null"),
+        };
+        format!("{{\"name\":{},\"href\":{},\"type\":{},\"size\":{},\"modified\":{}}}",
+                json_string(&entry.name),
+                json_string(&href(&base, entry)),
+                json_string(if entry.is_dir { "directory" } else { "file" }),
+                if entry.is_dir { String::from("null") } else { entry.size.to_string() },
+                modified)
+    }).collect();
+    format!("{{\"path\":{},\"parent\":{},\"entries\":[{}]}}",
+            json_string(&base),
+            parent,
+            items.join(","))
+}
+
+fn with_trailing_slash(path: &str) -> String {
+    if path.ends_with('/') {
+        path.to_string()
+    } else {
+        format!("{}/", path)
+    }
+}
+
+///The decoded parent of a directory path or None if
+/// the path is the root
+fn parent(base: &str) -> Option<String> {
+    let trimmed = base.trim_end_matches('/');
+    if trimmed.is_empty() {
+        return None;
+    }
+    let idx = trimmed.rfind('/').unwrap_or(0);
+    Some(trimmed[..=idx].to_string())
+}
+
+fn href(base: &str, entry: &Entry) -> String {
+    let mut ret = encode_path(base);
+    ret += &utf8_percent_encode(&entry.name, PATH_SEGMENT_ENCODE_SET).to_string();
+    if entry.is_dir {
+        ret.push('/');
+    }
+    ret
+}
+
+///Percent encode each segment of a decoded path
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET).to_string())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<Entry> {
+        let mut ret = vec![
+            Entry { name: String::from("b.txt"), is_dir: false, size: 3, modified: None },
+            Entry { name: String::from("z dir"), is_dir: true, size: 0, modified: None },
+            Entry { name: String::from("a.txt"), is_dir: false, size: 12, modified: None },
+        ];
+        sort_entries(&mut ret);
+        ret
+    }
+
+    #[test]
+    fn sorted() {
+        let names: Vec<String> = entries().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["z dir", "a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn html() {
+        let page = render_html("/files", &entries());
+        assert!(page.contains("<title>Index of /files/</title>"));
+        assert!(page.contains("<a href=\"/\">../</a>"));
+        assert!(page.contains("<a href=\"/files/z%20dir/\">z dir/</a>"));
+        assert!(page.contains("<a href=\"/files/a.txt\">a.txt</a></td><td>12</td>"));
+    }
+
+    #[test]
+    fn html_root_has_no_parent() {
+        let page = render_html("/", &entries());
+        assert!(!page.contains("../"));
+    }
+
+    #[test]
+    fn json() {
+        let body = render_json("/files/", &entries()[..2]);
+        assert_eq!(body, "{\"path\":\"/files/\",\"parent\":\"/\",\"entries\":[\
+{\"name\":\"z dir\",\"href\":\"/files/z%20dir/\",\"type\":\"directory\",\"size\":null,\"modified\":null},\
+{\"name\":\"a.txt\",\"href\":\"/files/a.txt\",\"type\":\"file\",\"size\":12,\"modified\":null}]}");
+    }
+
+    #[test]
+    fn disk() {
+        let listing = read_entries(Path::new("examples/public/files"), false).unwrap();
+        let names: Vec<String> = listing.into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec![".hidden", "a.txt"]);
+        let listing = read_entries(Path::new("examples/public/files"), true).unwrap();
+        let names: Vec<String> = listing.into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["a.txt"]);
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use base64;
+
+use bcrypt;
+
+use futures::Future;
+use futures::future::ok;
+use futures_cpupool::CpuPool;
+
+use hyper::header::Headers;
+use hyper::server::Request;
+
+use sha1::{Sha1, Digest};
+
+use super::compare::constant_time_eq;
+use super::random;
+
+///The request header handlers read the authenticated user from,
+/// any value sent by the client is removed
+pub const USER_HEADER: &str = "X-Authenticated-User";
+
+#[derive(Clone, Debug)]
+enum Credential {
+    Sha1(Vec<u8>),
+    Bcrypt(String),
+}
+
+impl Credential {
+    fn verify(&self, password: &str) -> bool {
+        match *self {
+            Credential::Sha1(ref digest) => constant_time_eq(&sha1(password), digest),
+            Credential::Bcrypt(ref hash) => bcrypt::verify(password, hash).unwrap_or(false),
+        }
+    }
+    fn cost(&self) -> u32 {
+        match *self {
+            Credential::Sha1(_) => 0,
+            Credential::Bcrypt(ref hash) => hash.get(4..6).and_then(|c| c.parse().ok()).unwrap_or(0),
+        }
+    }
+}
+
+///Users allowed in by HTTP Basic authentication
+#[derive(Clone, Debug)]
+pub struct BasicAuth {
+    realm: String,
+    users: HashMap<String, Credential>,
+    ///Checked for unknown users so they take as long
+    /// as the slowest known user
+    dummy: Credential,
+    ///A keyed digest of each user's password once it has
+    /// passed a bcrypt check, so only a user's first login or
+    /// a wrong password waits for bcrypt
+    verified: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    key: [u8; 16],
+    cost: u32,
+    ///Where bcrypt runs, off the event loop
+    pool: CpuPool,
+}
+
+///The outcome of checking a request's credentials
+pub enum Verdict {
+    ///Known straight away, with the user if they are let in
+    Now(Option<String>),
+    ///Waiting for bcrypt to finish on the pool
+    Pending(Box<dyn Future<Item = Option<String>, Error = ()> + Send>),
+}
+
+impl Verdict {
+    ///Block until the outcome is known
+    pub fn wait(self) -> Option<String> {
+        match self {
+            Verdict::Now(user) => user,
+            Verdict::Pending(fut) => fut.wait().unwrap_or(None),
+        }
+    }
+}
+
+impl BasicAuth {
+    pub fn new(realm: &str) -> BasicAuth {
+        BasicAuth {
+            realm: realm.to_string(),
+            users: HashMap::new(),
+            dummy: Credential::Sha1(vec![0; 20]),
+            verified: Arc::new(Mutex::new(HashMap::new())),
+            key: random::bytes(),
+            cost: bcrypt::DEFAULT_COST,
+            pool: CpuPool::new_num_cpus(),
+        }
+    }
+    ///The bcrypt cost `with_user` hashes passwords at,
+    /// 12 by default
+    pub fn with_cost(mut self, cost: u32) -> Self {
+        self.cost = cost;
+        self
+    }
+    ///Add a user, only a bcrypt hash of the password is kept
+    pub fn with_user(mut self, name: &str, password: &str) -> Self {
+        let hash = bcrypt::hash(password, self.cost).expect("bcrypt costs must be between 4 and 31");
+        self.add(name, Credential::Bcrypt(hash));
+        self
+    }
+    ///Add every user in an Apache style `.htpasswd` file,
+    /// entries must be `{SHA}` or bcrypt hashes
+    pub fn with_htpasswd<P: AsRef<Path>>(self, path: P) -> io::Result<Self> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        self.with_htpasswd_str(&contents)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+    ///Add every user in the contents of an `.htpasswd` file
+    pub fn with_htpasswd_str(mut self, contents: &str) -> Result<Self, String> {
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, ':');
+            let (name, hash) = match (parts.next(), parts.next()) {
+                (Some(name), Some(hash)) if !name.is_empty() => (name, hash),
+                _ => return Err(format!("Line {} is not a user and password hash", i + 1)),
+            };
+            let credential = if let Some(digest) = hash.strip_prefix("{SHA}") {
+                match base64::decode(digest) {
+                    Ok(ref digest) if digest.len() == 20 => Credential::Sha1(digest.clone()),
+                    _ => return Err(format!("Line {} has an invalid SHA1 hash", i + 1)),
+                }
+            } else if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$") {
+                Credential::Bcrypt(hash.to_string())
+            } else {
+                return Err(format!("Line {} uses an unsupported hash, only SHA1 and bcrypt are supported", i + 1))
+            };
+            self.add(name, credential);
+        }
+        Ok(self)
+    }
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+    ///The value of the `WWW-Authenticate` header sent with a 401
+    pub fn challenge(&self) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+    ///The user a request's `Authorization` header logs in as,
+    /// SHA1 hashes and passwords that already passed are checked
+    /// straight away and bcrypt hashes on a thread pool
+    pub fn authenticate(&self, headers: &Headers) -> Verdict {
+        let (name, password) = match credentials(headers) {
+            Some(credentials) => credentials,
+            None => return Verdict::Now(None),
+        };
+        let credential = match self.users.get(&name) {
+            Some(credential) => credential.clone(),
+            None => {
+                //take as long as a wrong password would
+                let dummy = self.dummy.clone();
+                return Verdict::Pending(Box::new(self.pool.spawn_fn(move || {
+                    dummy.verify(&password);
+                    ok(None)
+                })))
+            },
+        };
+        if let Credential::Sha1(_) = credential {
+            return Verdict::Now(if credential.verify(&password) { Some(name) } else { None })
+        }
+        let mut keyed = self.key.to_vec();
+        keyed.extend_from_slice(password.as_bytes());
+        let digest = sha1(&keyed);
+        let cached = self.verified.lock().ok()
+            .and_then(|verified| verified.get(&name).map(|d| constant_time_eq(d, &digest)))
+            .unwrap_or(false);
+        if cached {
+            return Verdict::Now(Some(name))
+        }
+        let verified = self.verified.clone();
+        Verdict::Pending(Box::new(self.pool.spawn_fn(move || {
+            if !credential.verify(&password) {
+                return ok(None)
+            }
+            if let Ok(mut verified) = verified.lock() {
+                verified.insert(name.clone(), digest);
+            }
+            ok(Some(name))
+        })))
+    }
+
+    ///Keep a user's credential, unknown users are checked against
+    /// a dummy hash as slow as the slowest user's
+    fn add(&mut self, name: &str, credential: Credential) {
+        if credential.cost() > self.dummy.cost() {
+            self.dummy = Credential::Bcrypt(format!("$2b${:02}${}", credential.cost(), ".".repeat(53)));
+        }
+        self.users.insert(name.to_string(), credential);
+    }
+}
+
+///The name and password in a request's `Authorization` header
+fn credentials(headers: &Headers) -> Option<(String, String)> {
+    let value = headers.get_raw("Authorization")
+        .and_then(|raw| raw.one())
+        .and_then(|line| str::from_utf8(line).ok())?;
+    let mut parts = value.trim().splitn(2, ' ');
+    let (scheme, encoded) = (parts.next()?, parts.next()?);
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None
+    }
+    let decoded = base64::decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    Some((parts.next()?.to_string(), parts.next()?.to_string()))
+}
+
+///The user a request was authenticated as
+pub fn user(req: &Request) -> Option<&str> {
+    req.headers().get_raw(USER_HEADER)
+        .and_then(|raw| raw.one())
+        .and_then(|line| str::from_utf8(line).ok())
+}
+
+fn sha1<T: AsRef<[u8]>>(password: T) -> Vec<u8> {
+    let mut sh = Sha1::default();
+    sh.input(password.as_ref());
+    sh.result().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &str, password: &str) -> Headers {
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", format!("Basic {}", base64::encode(&format!("{}:{}", name, password))));
+        headers
+    }
+
+    #[test]
+    fn in_code() {
+        let auth = BasicAuth::new("admin").with_cost(4).with_user("admin", "pa:ss");
+        assert_eq!(auth.dummy.cost(), 4);
+        assert_eq!(auth.authenticate(&headers("admin", "pa:ss")).wait(), Some(String::from("admin")));
+        assert_eq!(auth.authenticate(&headers("admin", "pa")).wait(), None);
+        assert_eq!(auth.authenticate(&headers("root", "pa:ss")).wait(), None);
+        assert_eq!(auth.authenticate(&Headers::new()).wait(), None);
+        let mut bearer = Headers::new();
+        bearer.set_raw("Authorization", "Bearer abc");
+        assert_eq!(auth.authenticate(&bearer).wait(), None);
+    }
+
+    #[test]
+    fn htpasswd() {
+        let auth = BasicAuth::new("files").with_htpasswd("examples/users.htpasswd").unwrap();
+        assert_eq!(auth.authenticate(&headers("alice", "wonderland")).wait(), Some(String::from("alice")));
+        assert_eq!(auth.authenticate(&headers("alice", "builder")).wait(), None);
+        assert_eq!(auth.authenticate(&headers("bob", "builder")).wait(), Some(String::from("bob")));
+        assert!(auth.verified.lock().unwrap().contains_key("alice"));
+        match auth.authenticate(&headers("alice", "wonderland")) {
+            Verdict::Now(user) => assert_eq!(user, Some(String::from("alice"))),
+            Verdict::Pending(_) => panic!("a verified password should not wait for bcrypt"),
+        }
+        assert!(matches!(auth.authenticate(&headers("alice", "builder")), Verdict::Pending(_)));
+        match auth.dummy {
+            Credential::Bcrypt(ref hash) => assert_eq!(bcrypt::verify("wonderland", hash).ok(), Some(false)),
+            Credential::Sha1(_) => panic!("the dummy should use bcrypt"),
+        }
+        assert_eq!(auth.dummy.cost(), 4);
+        assert_eq!(auth.authenticate(&headers("carol", "wonderland")).wait(), None);
+        assert!(BasicAuth::new("").with_htpasswd("examples/missing.htpasswd").is_err());
+        assert!(BasicAuth::new("").with_htpasswd_str("carol:$apr1$abc$def").is_err());
+        assert!(BasicAuth::new("").with_htpasswd_str("carol").is_err());
+    }
+
+    #[test]
+    fn challenge() {
+        assert_eq!(BasicAuth::new("my \"files\"").challenge(), "Basic realm=\"my \\\"files\\\"\", charset=\"UTF-8\"");
+    }
+}
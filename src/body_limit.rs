@@ -0,0 +1,149 @@
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::future::{ok, Either};
+use futures::sync::mpsc::Sender;
+
+use hyper::{Body, Chunk, Error};
+use hyper::header::ContentLength;
+use hyper::server::{Request, Response};
+
+use super::HyperResult;
+
+///Check if a request says its body is larger than `max`
+pub fn declared_too_large(req: &Request, max: u64) -> bool {
+    req.headers().get::<ContentLength>().map(|len| len.0 > max).unwrap_or(false)
+}
+
+///Replace a request's body with one fed by the returned `Pump`,
+/// which stops passing chunks along once `max` bytes have been read
+pub fn limit(req: Request, max: u64) -> (Request, Pump) {
+    let mut req = req;
+    let body = req.body_mut().take().unwrap_or_default();
+    let (tx, limited) = Body::pair();
+    req.set_body(limited);
+    (req, Pump {
+        body,
+        tx,
+        pending: None,
+        read: 0,
+        max,
+        exceeded: false,
+        done: false,
+    })
+}
+
+///Race a handler's response against its body's pump, when the
+/// body runs over its limit the handler is dropped and
+/// `on_exceeded` builds the response
+pub fn guard<F>(fut: HyperResult, pump: Pump, on_exceeded: F) -> HyperResult
+    where F: FnOnce() -> Response + 'static {
+    Box::new(
+        fut.select2(pump)
+            .then(move |res| -> HyperResult {
+                match res {
+                    Ok(Either::A((res, _))) => Box::new(ok(res)),
+                    Err(Either::A((e, _))) => Box::new(::futures::future::err(e)),
+                    Ok(Either::B((true, _))) => Box::new(ok(on_exceeded())),
+                    Ok(Either::B((false, fut))) => fut,
+                    Err(Either::B(((), fut))) => fut,
+                }
+            })
+    )
+}
+
+///Moves chunks from the client's body to the handler's, resolving
+/// with `true` if the body was larger than its limit
+pub struct Pump {
+    body: Body,
+    tx: Sender<Result<Chunk, Error>>,
+    pending: Option<Result<Chunk, Error>>,
+    read: u64,
+    max: u64,
+    exceeded: bool,
+    done: bool,
+}
+
+impl Future for Pump {
+    type Item = bool;
+    type Error = ();
+    fn poll(&mut self) -> Poll<bool, ()> {
+        loop {
+            if let Some(item) = self.pending.take() {
+                match self.tx.start_send(item) {
+                    Ok(AsyncSink::Ready) => (),
+                    Ok(AsyncSink::NotReady(item)) => {
+                        self.pending = Some(item);
+                        return Ok(Async::NotReady)
+                    },
+                    //the handler dropped its body
+                    Err(_) => return Ok(Async::Ready(self.exceeded)),
+                }
+            }
+            if self.tx.poll_complete().is_err() {
+                return Ok(Async::Ready(self.exceeded))
+            }
+            if self.done {
+                return Ok(Async::Ready(self.exceeded))
+            }
+            self.pending = match self.body.poll() {
+                Ok(Async::Ready(Some(chunk))) => {
+                    self.read += chunk.len() as u64;
+                    if self.read > self.max {
+                        self.exceeded = true;
+                        self.done = true;
+                        Some(Err(Error::TooLarge))
+                    } else {
+                        Some(Ok(chunk))
+                    }
+                },
+                Ok(Async::Ready(None)) => {
+                    self.done = true;
+                    None
+                },
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    self.done = true;
+                    Some(Err(e))
+                },
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, StatusCode, Uri};
+    use std::str::FromStr;
+
+    fn request(body: &'static str) -> Request {
+        let mut req = Request::new(Method::Post, Uri::from_str("/").unwrap());
+        req.set_body(body);
+        req
+    }
+
+    fn echo(req: Request) -> HyperResult {
+        Box::new(req.body().concat2().map(|body| Response::new().with_body(body)))
+    }
+
+    fn run(req: Request, max: u64) -> Response {
+        let (req, pump) = limit(req, max);
+        guard(echo(req), pump, || Response::new().with_status(StatusCode::PayloadTooLarge)).wait().unwrap()
+    }
+
+    #[test]
+    fn declared() {
+        let mut req = request("");
+        assert!(!declared_too_large(&req, 10));
+        req.headers_mut().set(ContentLength(11));
+        assert!(declared_too_large(&req, 10));
+    }
+
+    #[test]
+    fn streamed() {
+        let res = run(request("0123456789"), 10);
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(&*res.body().concat2().wait().unwrap() == b"0123456789");
+        let res = run(request("0123456789a"), 10);
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+    }
+}
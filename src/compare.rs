@@ -0,0 +1,8 @@
+///Compare two byte strings in time that depends only on
+/// their lengths, not on where they first differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
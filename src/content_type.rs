@@ -0,0 +1,63 @@
+use std::path::Path;
+
+///The Content-Type to send for a file based on its extension,
+/// if `gzipped` is true the trailing `.gz` is ignored
+pub fn for_path(path: &Path, gzipped: bool) -> &'static str {
+    let path = if gzipped && path.extension().map(|e| e == "gz").unwrap_or(false) {
+        Path::new(path.file_stem().unwrap_or_default())
+    } else {
+        path
+    };
+    let ext = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => ext.to_lowercase(),
+        None => return "application/octet-stream",
+    };
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "application/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "rss" => "application/rss+xml",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known() {
+        assert_eq!(for_path(Path::new("www/index.html"), false), "text/html; charset=utf-8");
+        assert_eq!(for_path(Path::new("app.WASM"), false), "application/wasm");
+        assert_eq!(for_path(Path::new("noext"), false), "application/octet-stream");
+    }
+
+    #[test]
+    fn gzipped() {
+        assert_eq!(for_path(Path::new("www/app.js.gz"), true), "application/javascript; charset=utf-8");
+        assert_eq!(for_path(Path::new("www/app.js.gz"), false), "application/gzip");
+    }
+}
@@ -0,0 +1,280 @@
+use std::str;
+
+use base64;
+
+use futures::{Future, Stream};
+use futures::future::ok;
+
+use hyper::{Body, Error, Method, StatusCode};
+use hyper::header::{ContentType, Cookie, Headers, SetCookie};
+use hyper::server::{Request, Response};
+
+use percent_encoding::percent_decode;
+
+use super::body_limit;
+use super::compare::constant_time_eq;
+use super::random;
+use super::scope::{Scope, Scoped};
+use super::sha256;
+
+///The request header a script can send the token in
+/// instead of a form field
+pub const HEADER: &str = "X-CSRF-Token";
+
+///The request header handlers read the token to render
+/// from, any value sent by the client is removed
+pub const ISSUED_HEADER: &str = "X-Csrf-Issued-Token";
+
+///Protection from cross site request forgery using signed
+/// double submit cookies. Every request is given a token, kept
+/// in a cookie, that a POST, PUT or DELETE has to send back in
+/// its `X-CSRF-Token` header or url encoded form body
+#[derive(Clone, Debug)]
+pub struct Csrf {
+    secret: Vec<u8>,
+    cookie_name: String,
+    field_name: String,
+    secure: bool,
+    max_form_size: u64,
+    exempt: Scoped<()>,
+}
+
+impl Default for Csrf {
+    fn default() -> Csrf {
+        Csrf::new()
+    }
+}
+
+impl Csrf {
+    ///Tokens are signed with a random secret, so they stop
+    /// being accepted when the server restarts
+    pub fn new() -> Csrf {
+        Csrf {
+            secret: random::bytes().to_vec(),
+            cookie_name: String::from("csrf_token"),
+            field_name: String::from("csrf_token"),
+            secure: false,
+            max_form_size: 1024 * 1024,
+            exempt: Scoped::new(),
+        }
+    }
+    ///Sign tokens with this secret, servers sharing it
+    /// accept each other's tokens
+    pub fn with_secret(mut self, secret: &[u8]) -> Self {
+        self.secret = secret.to_vec();
+        self
+    }
+    ///The cookie the token is kept in, `csrf_token` by default
+    pub fn with_cookie_name(mut self, name: &str) -> Self {
+        self.cookie_name = name.to_string();
+        self
+    }
+    ///The form field the token is posted in, `csrf_token` by default
+    pub fn with_field_name(mut self, name: &str) -> Self {
+        self.field_name = name.to_string();
+        self
+    }
+    ///Only send the cookie over https
+    pub fn with_secure_cookie(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+    ///The largest url encoded body read to find the token,
+    /// 1MB by default, larger ones get a 413
+    pub fn with_max_form_size(mut self, bytes: u64) -> Self {
+        self.max_form_size = bytes;
+        self
+    }
+    ///Skip the check for every path under `prefix`
+    pub fn with_exempt_prefix(mut self, prefix: &str) -> Self {
+        self.exempt.add(Scope::Prefix(prefix.to_string()), ());
+        self
+    }
+    ///Skip the check for a single route
+    pub fn with_exempt_route(mut self, method: Method, path: &str) -> Self {
+        self.exempt.add(Scope::Route(method, path.to_string()), ());
+        self
+    }
+    pub fn field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    ///The token from a request's cookie if this server signed
+    /// it, otherwise a new one and `true`
+    pub fn issue(&self, headers: &Headers) -> (String, bool) {
+        let existing = headers.get::<Cookie>()
+            .and_then(|cookie| cookie.get(&self.cookie_name))
+            .filter(|token| self.is_signed(token));
+        match existing {
+            Some(token) => (token.to_string(), false),
+            None => {
+                let nonce = base64::encode_config(&random::bytes(), base64::URL_SAFE_NO_PAD);
+                let token = format!("{}.{}", nonce, self.sign(&nonce));
+                (token, true)
+            },
+        }
+    }
+    ///Add the cookie for a newly issued token to a response
+    pub fn set_cookie(&self, res: &mut Response, token: &str) {
+        let cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Lax{}",
+                             self.cookie_name, token,
+                             if self.secure { "; Secure" } else { "" });
+        let existing = res.headers_mut().get_mut::<SetCookie>().map(|set| set.0.push(cookie.clone()));
+        if existing.is_none() {
+            res.headers_mut().set(SetCookie(vec!(cookie)));
+        }
+    }
+    ///If a request has to send its token back, GET, HEAD, OPTIONS
+    /// and TRACE requests or exempt routes don't
+    pub fn applies(&self, method: &Method, path: &str) -> bool {
+        match *method {
+            Method::Get | Method::Head | Method::Options | Method::Trace => false,
+            _ => self.exempt.find(method, path).is_none(),
+        }
+    }
+    ///Resolves with the request if it sent back the token it was
+    /// issued, otherwise the status to refuse it with. A url encoded
+    /// body is read to find the token's field and then put back for
+    /// the handler
+    pub fn verify(&self, req: Request) -> Box<dyn Future<Item = Result<Request, StatusCode>, Error = Error>> {
+        let expected = match token(&req) {
+            Some(token) => token.to_string(),
+            None => return Box::new(ok(Err(StatusCode::Forbidden))),
+        };
+        if let Some(sent) = req.headers().get_raw(HEADER).and_then(|raw| raw.one()) {
+            let valid = constant_time_eq(sent, expected.as_bytes());
+            return Box::new(ok(if valid { Ok(req) } else { Err(StatusCode::Forbidden) }))
+        }
+        let is_form = req.headers().get::<ContentType>()
+            .map(|ct| ct.type_() == "application" && ct.subtype() == "x-www-form-urlencoded")
+            .unwrap_or(false);
+        if !is_form {
+            return Box::new(ok(Err(StatusCode::Forbidden)))
+        }
+        let max = self.max_form_size;
+        if body_limit::declared_too_large(&req, max) {
+            return Box::new(ok(Err(StatusCode::PayloadTooLarge)))
+        }
+        let field = self.field_name.clone();
+        let mut req = req;
+        let body = req.body_mut().take().unwrap_or_default();
+        let read = body.fold(Vec::new(), move |mut body, chunk| {
+            if body.len() as u64 + chunk.len() as u64 > max {
+                return Err(Error::TooLarge)
+            }
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        });
+        Box::new(read.then(move |body| {
+            let body = match body {
+                Ok(body) => body,
+                Err(Error::TooLarge) => return Ok(Err(StatusCode::PayloadTooLarge)),
+                Err(e) => return Err(e),
+            };
+            let valid = form_field(&body, &field)
+                .map(|sent| constant_time_eq(sent.as_bytes(), expected.as_bytes()))
+                .unwrap_or(false);
+            req.set_body(Body::from(body));
+            Ok(if valid { Ok(req) } else { Err(StatusCode::Forbidden) })
+        }))
+    }
+
+    fn sign(&self, nonce: &str) -> String {
+        base64::encode_config(&sha256::hmac(&self.secret, nonce.as_bytes()), base64::URL_SAFE_NO_PAD)
+    }
+    fn is_signed(&self, token: &str) -> bool {
+        let mut parts = token.splitn(2, '.');
+        match (parts.next(), parts.next()) {
+            (Some(nonce), Some(mac)) => constant_time_eq(self.sign(nonce).as_bytes(), mac.as_bytes()),
+            _ => false,
+        }
+    }
+}
+
+///The token to render in a form posted by this request's page
+pub fn token(req: &Request) -> Option<&str> {
+    req.headers().get_raw(ISSUED_HEADER)
+        .and_then(|raw| raw.one())
+        .and_then(|line| str::from_utf8(line).ok())
+}
+
+///The first value of a field in a url encoded form
+fn form_field(body: &[u8], name: &str) -> Option<String> {
+    body.split(|b| *b == b'&').find_map(|pair| {
+        let mut parts = pair.splitn(2, |b| *b == b'=');
+        let key = decode(parts.next()?);
+        if key == name {
+            Some(decode(parts.next().unwrap_or_default()))
+        } else {
+            None
+        }
+    })
+}
+
+fn decode(part: &[u8]) -> String {
+    let spaced: Vec<u8> = part.iter().map(|b| if *b == b'+' { b' ' } else { *b }).collect();
+    percent_decode(&spaced).decode_utf8_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Uri;
+    use std::str::FromStr;
+
+    fn request(method: Method, issued: &str) -> Request {
+        let mut req = Request::new(method, Uri::from_str("/form").unwrap());
+        req.headers_mut().set_raw(ISSUED_HEADER, issued.to_string());
+        req
+    }
+
+    fn form(issued: &str, body: &str) -> Result<Request, StatusCode> {
+        let mut req = request(Method::Post, issued);
+        req.headers_mut().set(ContentType::form_url_encoded());
+        req.set_body(body.to_string());
+        Csrf::new().with_max_form_size(64).verify(req).wait().unwrap()
+    }
+
+    #[test]
+    fn issue() {
+        let csrf = Csrf::new().with_secret(b"secret");
+        let (token, new) = csrf.issue(&Headers::new());
+        assert!(new);
+        let mut headers = Headers::new();
+        let mut cookie = Cookie::new();
+        cookie.set("csrf_token", token.clone());
+        headers.set(cookie);
+        assert_eq!(csrf.issue(&headers), (token.clone(), false));
+        assert!(Csrf::new().with_secret(b"other").issue(&headers).1);
+        let mut res = Response::new();
+        csrf.with_secure_cookie().set_cookie(&mut res, &token);
+        assert_eq!(res.headers().get::<SetCookie>().unwrap().0,
+                   vec!(format!("csrf_token={}; Path=/; HttpOnly; SameSite=Lax; Secure", token)));
+    }
+
+    #[test]
+    fn verify() {
+        let mut req = request(Method::Post, "abc");
+        req.headers_mut().set_raw(HEADER, "abc");
+        assert!(Csrf::new().verify(req).wait().unwrap().is_ok());
+        let mut req = request(Method::Post, "abc");
+        req.headers_mut().set_raw(HEADER, "abd");
+        assert_eq!(Csrf::new().verify(req).wait().unwrap().err(), Some(StatusCode::Forbidden));
+        let req = form("a+b/c", "name=x&csrf_token=a%2Bb%2Fc").unwrap();
+        assert_eq!(&*req.body().concat2().wait().unwrap(), b"name=x&csrf_token=a%2Bb%2Fc");
+        assert_eq!(form("abc", "name=x").err(), Some(StatusCode::Forbidden));
+        assert_eq!(form("abc", "csrf_token=abd").err(), Some(StatusCode::Forbidden));
+        assert_eq!(form("abc", &format!("csrf_token=abc&name={}", "x".repeat(64))).err(), Some(StatusCode::PayloadTooLarge));
+        assert!(Csrf::new().verify(request(Method::Post, "abc")).wait().unwrap().is_err());
+    }
+
+    #[test]
+    fn applies() {
+        let csrf = Csrf::new().with_exempt_prefix("/api").with_exempt_route(Method::Put, "/hook");
+        assert!(!csrf.applies(&Method::Get, "/form"));
+        assert!(csrf.applies(&Method::Post, "/form"));
+        assert!(!csrf.applies(&Method::Post, "/api/items"));
+        assert!(!csrf.applies(&Method::Put, "/hook"));
+        assert!(csrf.applies(&Method::Delete, "/hook"));
+    }
+}
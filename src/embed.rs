@@ -0,0 +1,197 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use super::content_type;
+use super::file_cache::{etag_for, StaticFile};
+use super::pony::ETag;
+
+///A file compiled into the binary by `generate`
+#[derive(Debug)]
+pub struct EmbeddedFile {
+    ///The path relative to the embedded directory, always
+    /// using `/` as a separator and without a leading `/`
+    pub path: &'static str,
+    pub contents: &'static [u8],
+    ///The gzip encoded contents, only present if smaller
+    /// than `contents`
+    pub gzip: Option<&'static [u8]>,
+    ///The quoted sha1 of `contents`
+    pub sha1: &'static str,
+    pub content_type: &'static str,
+    ///Milliseconds since the unix epoch
+    pub modified: u64,
+}
+
+///A directory compiled into the binary by `generate`,
+/// files are sorted by path
+#[derive(Debug)]
+pub struct EmbeddedBundle {
+    pub files: &'static [EmbeddedFile],
+}
+
+impl EmbeddedBundle {
+    ///Find a file by its relative path
+    pub fn get(&self, path: &str) -> Option<&'static EmbeddedFile> {
+        self.files.binary_search_by(|f| f.path.cmp(path))
+            .ok()
+            .map(|idx| &self.files[idx])
+    }
+    ///The `(path, length, modified)` of every file
+    pub fn flat_files(&self) -> impl Iterator<Item = (&'static str, u64, Option<SystemTime>)> {
+        self.files.iter().map(|f| (f.path, f.contents.len() as u64, Some(f.modified_time())))
+    }
+}
+
+impl EmbeddedFile {
+    ///The contents and headers to serve for this file,
+    /// `gzipped` selects the gzip encoded version if one exists
+    pub fn to_static_file(&self, gzipped: bool, etag: ETag) -> Option<StaticFile> {
+        let contents = if gzipped {
+            self.gzip?
+        } else {
+            self.contents
+        };
+        let modified = Some(self.modified_time());
+        let etag = match etag {
+            ETag::Sha1 => Some(self.sha1.to_string()),
+            _ => etag_for(etag, self.contents, modified),
+        };
+        Some(StaticFile {
+            contents: Bytes::from_static(contents),
+            etag,
+            content_type: self.content_type,
+            modified,
+        })
+    }
+    ///When the file was last modified before being embedded
+    pub fn modified_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.modified)
+    }
+}
+
+///Compile a directory into a Rust expression for an
+/// `EmbeddedBundle`, meant to be called from a build script.
+/// Dotfiles are skipped, gzip versions of each file are written
+/// next to `out_file` and included when they are smaller than
+/// the original.
+///
+/// In build.rs
+/// `pony::embed::generate(Path::new("www"), &Path::new(&env::var("OUT_DIR").unwrap()).join("www.rs"))`
+///
+/// In your crate
+/// `static WWW: EmbeddedBundle = include!(concat!(env!("OUT_DIR"), "/www.rs"));`
+pub fn generate(dir: &Path, out_file: &Path) -> io::Result<()> {
+    let dir = fs::canonicalize(dir)?;
+    let mut files = vec!();
+    collect(&dir, &dir, &mut files)?;
+    files.sort();
+    let gz_dir = {
+        let mut name = out_file.file_stem().unwrap_or_default().to_os_string();
+        name.push("_gz");
+        out_file.with_file_name(name)
+    };
+    fs::create_dir_all(&gz_dir)?;
+    let mut out = String::from("::pony::embed::EmbeddedBundle { files: &[\n");
+    for (idx, (relative, path)) in files.iter().enumerate() {
+        let mut contents = vec!();
+        File::open(path)?.read_to_end(&mut contents)?;
+        let md = fs::metadata(path)?;
+        let modified = md.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() * 1000 + d.subsec_millis() as u64)
+            .unwrap_or(0);
+        let mut encoder = GzEncoder::new(vec!(), Compression::best());
+        encoder.write_all(&contents)?;
+        let compressed = encoder.finish()?;
+        let gzip = if compressed.len() < contents.len() {
+            let gz_path = gz_dir.join(format!("{}.gz", idx));
+            File::create(&gz_path)?.write_all(&compressed)?;
+            format!("Some(include_bytes!({:?}))", gz_path.to_string_lossy())
+        } else {
+            String::from("None")
+        };
+        out += &format!("    ::pony::embed::EmbeddedFile {{ path: {:?}, contents: include_bytes!({:?}), gzip: {}, sha1: {:?}, content_type: {:?}, modified: {} }},\n",
+                        relative,
+                        path.to_string_lossy(),
+                        gzip,
+                        etag_for(ETag::Sha1, &contents, None).unwrap_or_default(),
+                        content_type::for_path(path, false),
+                        modified);
+        println!("cargo:rerun-if-changed={}", path.to_string_lossy());
+    }
+    out += "] }\n";
+    println!("cargo:rerun-if-changed={}", dir.to_string_lossy());
+    File::create(out_file)?.write_all(out.as_bytes())
+}
+
+///Recursively find every file under dir, skipping dotfiles
+fn collect(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_dir() {
+            collect(root, &path, files)?;
+        } else {
+            let relative = path.strip_prefix(root)
+                .map_err(|e| io::Error::other(e.to_string()))?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<String>>()
+                .join("/");
+            files.push((relative, path));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_support::{temp_path, BUNDLE};
+
+    #[test]
+    fn get() {
+        assert_eq!(BUNDLE.get("index.html").unwrap().contents, b"<h1>embedded</h1>");
+        assert!(BUNDLE.get("missing.html").is_none());
+    }
+
+    #[test]
+    fn static_file() {
+        let file = BUNDLE.get("index.html").unwrap();
+        let plain = file.to_static_file(false, ETag::Sha1).unwrap();
+        assert_eq!(&plain.contents[..], b"<h1>embedded</h1>");
+        assert_eq!(plain.etag, Some(String::from("\"index\"")));
+        let gz = file.to_static_file(true, ETag::LastModified).unwrap();
+        assert_eq!(&gz.contents[..], b"gzipped");
+        assert_eq!(gz.etag, Some(String::from("\"2000\"")));
+        assert!(BUNDLE.get("css/site.css").unwrap().to_static_file(true, ETag::None).is_none());
+    }
+
+    #[test]
+    fn generated() {
+        let out_dir = temp_path("embed");
+        fs::create_dir_all(&out_dir).unwrap();
+        let out_file = out_dir.join("public.rs");
+        generate(Path::new("examples/public"), &out_file).unwrap();
+        let mut code = String::new();
+        File::open(&out_file).unwrap().read_to_string(&mut code).unwrap();
+        assert!(code.starts_with("::pony::embed::EmbeddedBundle { files: &["));
+        assert!(code.contains("path: \"files/a.txt\""));
+        assert!(code.contains("path: \"index.html\""));
+        assert!(!code.contains(".hidden"));
+        let files = code.find("path: \"files/a.txt\"").unwrap();
+        let index = code.find("path: \"index.html\"").unwrap();
+        assert!(files < index, "generated files were not sorted");
+        fs::remove_dir_all(&out_dir).unwrap();
+    }
+}
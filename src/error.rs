@@ -0,0 +1,71 @@
+use std::error::Error;
+use std::fmt;
+
+use hyper::{Method, StatusCode};
+
+///A single problem found while configuring a `PonyBuilder`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigProblem {
+    ///A static directory or other source could not be used
+    Source(String),
+    ///An archive could not be opened or indexed
+    Archive { path: String, message: String },
+    ///An error page could not be read
+    ErrorPage { status: StatusCode, path: String, message: String },
+    ///The access log could not be opened
+    AccessLog { target: String, message: String },
+    ///An `.htpasswd` file could not be read
+    Htpasswd { path: String, message: String },
+    ///More than one handler was added for a method and path
+    ConflictingRoute { method: Method, path: String },
+    ///More than one static mount was added for a url prefix
+    ConflictingMount(String),
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigProblem::Source(ref msg) => write!(f, "{}", msg),
+            ConfigProblem::Archive { ref path, ref message } => write!(f, "Unable to open archive {:?}\n{}", path, message),
+            ConfigProblem::ErrorPage { status, ref path, ref message } => write!(f, "Unable to read {} error page {:?}\n{}", u16::from(status), path, message),
+            ConfigProblem::AccessLog { ref target, ref message } => write!(f, "Unable to open access log {}\n{}", target, message),
+            ConfigProblem::Htpasswd { ref path, ref message } => write!(f, "Unable to read htpasswd file {:?}\n{}", path, message),
+            ConfigProblem::ConflictingRoute { ref method, ref path } => write!(f, "More than one {} handler for {:?}", method, path),
+            ConfigProblem::ConflictingMount(ref prefix) => write!(f, "More than one static mount for {:?}", prefix),
+        }
+    }
+}
+
+///Every problem found while building a `Pony`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PonyError {
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl fmt::Display for PonyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} configuration problem(s)", self.problems.len())?;
+        for problem in &self.problems {
+            write!(f, "\n- {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for PonyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let err = PonyError {
+            problems: vec![
+                ConfigProblem::ConflictingRoute { method: Method::Get, path: String::from("/get") },
+                ConfigProblem::ConflictingMount(String::from("/assets")),
+            ],
+        };
+        assert_eq!(err.to_string(), "2 configuration problem(s)\n- More than one GET handler for \"/get\"\n- More than one static mount for \"/assets\"");
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use bytes::Bytes;
+
+use hyper::{Chunk, StatusCode};
+use hyper::server::Response;
+use hyper::header::{q, Accept, ContentLength, ContentType, Headers};
+use hyper::mime;
+
+use super::content_type;
+use super::escape::{html_escape, json_string};
+use super::request_id;
+
+///A page sent in place of an empty error response
+#[derive(Clone, Debug)]
+pub struct ErrorPage {
+    pub contents: Bytes,
+    pub content_type: &'static str,
+}
+
+impl ErrorPage {
+    ///Read a page from disk, the content type is
+    /// based on the file's extension
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<ErrorPage> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Error page is a directory\n{:?}", path)));
+        }
+        let mut contents = vec!();
+        File::open(path)?.read_to_end(&mut contents)?;
+        Ok(ErrorPage {
+            contents: Bytes::from(contents),
+            content_type: content_type::for_path(path, false),
+        })
+    }
+}
+
+///The pages to send for each error status, every page
+/// is read once when it is added.
+/// Clients that accept `application/json` get a json body
+/// instead of the page, statuses without a page are sent
+/// with an empty body. Once request ids are used any
+/// `{{request_id}}` in a page is replaced with the request's id
+#[derive(Clone, Debug, Default)]
+pub struct ErrorPages {
+    pages: HashMap<u16, ErrorPage>,
+    request_ids: bool,
+}
+
+impl ErrorPages {
+    pub fn new() -> ErrorPages {
+        ErrorPages::default()
+    }
+    ///Add or replace the page for a status
+    pub fn insert(&mut self, status: StatusCode, page: ErrorPage) {
+        self.pages.insert(u16::from(status), page);
+    }
+    ///Put the id `Pony` gave each request in pages and json
+    /// bodies, without this any `X-Request-Id` is ignored
+    pub fn use_request_ids(&mut self) {
+        self.request_ids = true;
+    }
+    ///The page for a status if one was added
+    pub fn get(&self, status: StatusCode) -> Option<&ErrorPage> {
+        self.pages.get(&u16::from(status))
+    }
+    ///Build the response for an error status
+    pub fn response(&self, status: StatusCode, req_headers: &Headers) -> Response {
+        let res = Response::new().with_status(status);
+        let request_id = if self.request_ids {
+            request_id::from_headers(req_headers)
+        } else {
+            None
+        };
+        if prefers_json(req_headers) {
+            let body = json_body(status, request_id);
+            return res.with_header(ContentLength(body.len() as u64))
+                .with_header(ContentType::json())
+                .with_body(body)
+        }
+        let page = match self.get(status) {
+            Some(page) => page,
+            None => return res,
+        };
+        let contents = match find(&page.contents, PLACEHOLDER) {
+            Some(idx) => {
+                let mut contents = Vec::with_capacity(page.contents.len());
+                contents.extend_from_slice(&page.contents[..idx]);
+                contents.extend_from_slice(html_escape(request_id.unwrap_or("")).as_bytes());
+                contents.extend_from_slice(&page.contents[idx + PLACEHOLDER.len()..]);
+                Bytes::from(contents)
+            },
+            None => page.contents.clone(),
+        };
+        res.with_header(ContentLength(contents.len() as u64))
+            .with_header(ContentType(page.content_type.parse().expect("invalid content type")))
+            .with_body(Chunk::from(contents))
+    }
+}
+
+const PLACEHOLDER: &[u8] = b"{{request_id}}";
+
+///The position of the first `needle` in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+///`{"status": 404, "error": "Not Found", "request_id": "..."}`
+fn json_body(status: StatusCode, request_id: Option<&str>) -> String {
+    let mut body = format!("{{\"status\": {}, \"error\": {}",
+                           u16::from(status),
+                           json_string(status.canonical_reason().unwrap_or("Unknown")));
+    if let Some(id) = request_id {
+        body += &format!(", \"request_id\": {}", json_string(id));
+    }
+    body.push('}');
+    body
+}
+
+///Check if a request's Accept header ranks json
+/// at least as high as anything else it accepts
+pub fn prefers_json(headers: &Headers) -> bool {
+    let accept = match headers.get::<Accept>() {
+        Some(accept) => accept,
+        None => return false,
+    };
+    let is_json = |m: &mime::Mime| m.type_() == mime::APPLICATION && (m.subtype() == mime::JSON || m.suffix() == Some(mime::JSON));
+    let json = accept.iter().filter(|item| is_json(&item.item)).map(|item| item.quality).max();
+    let other = accept.iter().filter(|item| !is_json(&item.item)).map(|item| item.quality).max();
+    match json {
+        Some(json) => json > q(0) && other.map(|other| json >= other).unwrap_or(true),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, Stream};
+
+    fn body(res: Response) -> String {
+        String::from_utf8(res.body().concat2().wait().unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn load() {
+        let page = ErrorPage::load("examples/public/404.html").unwrap();
+        assert_eq!(page.content_type, "text/html; charset=utf-8");
+        assert!(ErrorPage::load("examples/public").is_err());
+        assert!(ErrorPage::load("junk.html").is_err());
+    }
+
+    #[test]
+    fn pages() {
+        let mut pages = ErrorPages::new();
+        pages.insert(StatusCode::ServiceUnavailable, ErrorPage {
+            contents: Bytes::from_static(b"down"),
+            content_type: "text/plain; charset=utf-8",
+        });
+        let res = pages.response(StatusCode::ServiceUnavailable, &Headers::new());
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(res.headers().get::<ContentType>().unwrap().to_string(), "text/plain; charset=utf-8");
+        assert_eq!(body(res), "down");
+        let res = pages.response(StatusCode::NotFound, &Headers::new());
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(body(res), "");
+    }
+
+    #[test]
+    fn json() {
+        let mut pages = ErrorPages::new();
+        pages.insert(StatusCode::NotFound, ErrorPage::load("examples/public/404.html").unwrap());
+        let mut headers = Headers::new();
+        headers.set_raw("Accept", "application/json");
+        let res = pages.response(StatusCode::NotFound, &headers);
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(res.headers().get::<ContentType>(), Some(&ContentType::json()));
+        assert_eq!(body(res), "{\"status\": 404, \"error\": \"Not Found\"}");
+        headers.set_raw(request_id::HEADER, "abc");
+        let res = pages.response(StatusCode::NotFound, &headers);
+        assert_eq!(body(res), "{\"status\": 404, \"error\": \"Not Found\"}");
+        pages.use_request_ids();
+        let res = pages.response(StatusCode::NotFound, &headers);
+        assert_eq!(body(res), "{\"status\": 404, \"error\": \"Not Found\", \"request_id\": \"abc\"}");
+    }
+
+    #[test]
+    fn prefers() {
+        let accepts = |accept: &str| {
+            let mut headers = Headers::new();
+            headers.set_raw("Accept", accept.to_string());
+            prefers_json(&headers)
+        };
+        assert!(accepts("application/json"));
+        assert!(accepts("application/problem+json, */*"));
+        assert!(accepts("text/html;q=0.5, application/json"));
+        assert!(!accepts("application/json;q=0, text/html"));
+        assert!(!accepts("text/html, application/json;q=0.9"));
+        assert!(!accepts("text/html"));
+        assert!(!prefers_json(&Headers::new()));
+    }
+
+    #[test]
+    fn request_id() {
+        let mut pages = ErrorPages::new();
+        pages.insert(StatusCode::InternalServerError, ErrorPage {
+            contents: Bytes::from_static(b"<p>error {{request_id}}</p>"),
+            content_type: "text/html; charset=utf-8",
+        });
+        let mut headers = Headers::new();
+        headers.set_raw(request_id::HEADER, "<b>");
+        assert_eq!(body(pages.response(StatusCode::InternalServerError, &headers)), "<p>error </p>");
+        pages.use_request_ids();
+        let res = pages.response(StatusCode::InternalServerError, &headers);
+        assert_eq!(res.headers().get::<ContentLength>(), Some(&ContentLength(22)));
+        assert_eq!(body(res), "<p>error &lt;b&gt;</p>");
+        assert_eq!(body(pages.response(StatusCode::InternalServerError, &Headers::new())), "<p>error </p>");
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use sha1::{Sha1, Digest};
+
+use super::content_type;
+use super::pony::ETag;
+
+///A static file's contents along with the
+/// headers derived from them
+#[derive(Clone, Debug)]
+pub struct StaticFile {
+    pub contents: Bytes,
+    pub etag: Option<String>,
+    pub content_type: &'static str,
+    pub modified: Option<SystemTime>,
+}
+
+impl StaticFile {
+    ///Build a static file from its contents, path is used
+    /// to determine the content type. If `gzipped` is true
+    /// the contents are gzip encoded and the `.gz` extension
+    /// is ignored
+    pub fn new(contents: Vec<u8>, path: &Path, gzipped: bool, etag: ETag, modified: Option<SystemTime>) -> StaticFile {
+        StaticFile {
+            etag: etag_for(etag, &contents, modified),
+            content_type: content_type::for_path(path, gzipped),
+            contents: Bytes::from(contents),
+            modified,
+        }
+    }
+    ///Read a file from disk
+    pub fn load(path: &Path, gzipped: bool, etag: ETag) -> io::Result<StaticFile> {
+        let md = fs::metadata(path)?;
+        let mut contents = Vec::with_capacity(md.len() as usize);
+        File::open(path)?.read_to_end(&mut contents)?;
+        Ok(StaticFile::new(contents, path, gzipped, etag, md.modified().ok()))
+    }
+}
+
+///Compute the value of an ETag header
+pub fn etag_for(etag: ETag, contents: &[u8], modified: Option<SystemTime>) -> Option<String> {
+    match etag {
+        ETag::None => None,
+        ETag::LastModified => {
+            let d = modified?.duration_since(UNIX_EPOCH).ok()?;
+            Some(format!("\"{}\"", (d.as_secs() * 1000) + d.subsec_millis() as u64))
+        },
+        ETag::Sha1 => {
+            let mut sh = Sha1::default();
+            sh.input(contents);
+            let hex: String = sh.result().iter().map(|b| format!("{:02x}", b)).collect();
+            Some(format!("\"{}\"", hex))
+        },
+    }
+}
+
+///Counters for a `FileCache`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+struct CacheEntry {
+    file: StaticFile,
+    len: u64,
+    last_used: u64,
+}
+
+///A bounded, least recently used, cache of static files.
+/// Entries are invalidated when the file's modified time
+/// or length no longer matches what was cached
+pub struct FileCache {
+    max_bytes: usize,
+    max_file_bytes: usize,
+    entries: HashMap<(PathBuf, ETag), CacheEntry>,
+    ///Every entry's key by when it was last used, oldest first
+    order: BTreeMap<u64, (PathBuf, ETag)>,
+    tick: u64,
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl FileCache {
+    ///Create a cache holding at most `max_bytes` of file
+    /// contents, files larger than `max_file_bytes` are
+    /// never cached
+    pub fn new(max_bytes: usize, max_file_bytes: usize) -> FileCache {
+        FileCache {
+            max_bytes,
+            max_file_bytes,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            bytes: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+    ///Get a file from the cache, reading it from disk
+    /// if it is missing or has changed
+    pub fn get(&mut self, path: &Path, gzipped: bool, etag: ETag) -> io::Result<StaticFile> {
+        let md = fs::metadata(path)?;
+        if let Some(file) = self.cached(path, etag, &md) {
+            return Ok(file)
+        }
+        let file = StaticFile::load(path, gzipped, etag)?;
+        self.insert(path, etag, &file);
+        Ok(file)
+    }
+    ///Like `get`, but the lock is only held to look the file up
+    /// and to store it, never while it is read from disk
+    pub fn get_shared(cache: &Mutex<FileCache>, path: &Path, gzipped: bool, etag: ETag) -> io::Result<StaticFile> {
+        let md = fs::metadata(path)?;
+        if let Some(file) = cache.lock().ok().and_then(|mut cache| cache.cached(path, etag, &md)) {
+            return Ok(file)
+        }
+        let file = StaticFile::load(path, gzipped, etag)?;
+        if let Ok(mut cache) = cache.lock() {
+            cache.insert(path, etag, &file);
+        }
+        Ok(file)
+    }
+    ///The current counters for this cache
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+            bytes: self.bytes,
+        }
+    }
+    ///Remove every entry, the hit and miss counters are kept
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+
+    ///A cached file if it still matches the file on disk
+    fn cached(&mut self, path: &Path, etag: ETag, md: &Metadata) -> Option<StaticFile> {
+        let key = (path.to_path_buf(), etag);
+        let fresh = self.entries.get(&key)
+            .map(|entry| entry.file.modified == md.modified().ok() && entry.len == md.len())
+            .unwrap_or(false);
+        if !fresh {
+            self.misses += 1;
+            return None
+        }
+        self.hits += 1;
+        self.touch(&key);
+        self.entries.get(&key).map(|entry| entry.file.clone())
+    }
+
+    ///Store a file read from disk, unless another request
+    /// already stored the same version of it
+    fn insert(&mut self, path: &Path, etag: ETag, file: &StaticFile) {
+        let key = (path.to_path_buf(), etag);
+        let len = file.contents.len();
+        let stored = self.entries.get(&key)
+            .map(|entry| entry.file.modified == file.modified && entry.len == len as u64)
+            .unwrap_or(false);
+        if stored {
+            return
+        }
+        self.remove(&key);
+        if len > self.max_file_bytes || len > self.max_bytes {
+            return
+        }
+        while self.bytes + len > self.max_bytes {
+            self.evict();
+        }
+        self.tick += 1;
+        self.bytes += len;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, CacheEntry {
+            file: file.clone(),
+            len: len as u64,
+            last_used: self.tick,
+        });
+    }
+
+    fn touch(&mut self, key: &(PathBuf, ETag)) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.order.remove(&entry.last_used);
+            entry.last_used = self.tick;
+            self.order.insert(self.tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &(PathBuf, ETag)) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.last_used);
+            self.bytes -= entry.file.contents.len();
+        }
+    }
+
+    fn evict(&mut self) {
+        if let Some((_, key)) = self.order.pop_first() {
+            self.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use super::super::test_support::temp_file;
+
+    #[test]
+    fn hits_and_misses() {
+        let mut cache = FileCache::new(1024, 1024);
+        let path = Path::new("examples/public/index.html");
+        let first = cache.get(path, false, ETag::Sha1).unwrap();
+        let second = cache.get(path, false, ETag::Sha1).unwrap();
+        assert_eq!(first.contents, second.contents);
+        assert_eq!(first.etag, second.etag);
+        assert_eq!(first.content_type, "text/html; charset=utf-8");
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+        assert_eq!(stats.bytes, first.contents.len());
+    }
+
+    #[test]
+    fn invalidated_on_change() {
+        let path = temp_file("change.txt", b"one");
+        let mut cache = FileCache::new(1024, 1024);
+        assert_eq!(&cache.get(&path, false, ETag::None).unwrap().contents[..], b"one");
+        File::create(&path).unwrap().write_all(b"three").unwrap();
+        assert_eq!(&cache.get(&path, false, ETag::None).unwrap().contents[..], b"three");
+        assert_eq!(cache.stats().misses, 2);
+        fs::remove_file(&path).unwrap();
+        assert!(cache.get(&path, false, ETag::None).is_err());
+    }
+
+    #[test]
+    fn size_limits() {
+        let small = temp_file("small.txt", b"12345");
+        let other = temp_file("other.txt", b"67890");
+        let large = temp_file("large.txt", b"0123456789");
+        let mut cache = FileCache::new(8, 6);
+        cache.get(&large, false, ETag::None).unwrap();
+        assert_eq!(cache.stats().entries, 0);
+        cache.get(&small, false, ETag::None).unwrap();
+        cache.get(&other, false, ETag::None).unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (1, 5));
+        cache.get(&other, false, ETag::None).unwrap();
+        assert_eq!(cache.stats().hits, 1);
+        for path in [small, other, large].iter() {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn least_recently_used() {
+        let paths: Vec<PathBuf> = ["lru-a.txt", "lru-b.txt", "lru-c.txt"].iter()
+            .map(|name| temp_file(name, b"1234"))
+            .collect();
+        let cache = Mutex::new(FileCache::new(8, 8));
+        FileCache::get_shared(&cache, &paths[0], false, ETag::None).unwrap();
+        FileCache::get_shared(&cache, &paths[1], false, ETag::None).unwrap();
+        FileCache::get_shared(&cache, &paths[0], false, ETag::None).unwrap();
+        FileCache::get_shared(&cache, &paths[2], false, ETag::None).unwrap();
+        let mut cache = cache.into_inner().unwrap();
+        assert_eq!((cache.stats().entries, cache.stats().hits), (2, 1));
+        cache.get(&paths[0], false, ETag::None).unwrap();
+        assert_eq!(cache.stats().hits, 2);
+        cache.get(&paths[1], false, ETag::None).unwrap();
+        assert_eq!(cache.stats().misses, 4);
+        assert_eq!(cache.order.len(), cache.entries.len());
+        for path in &paths {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn etags() {
+        assert_eq!(etag_for(ETag::None, b"abc", None), None);
+        assert_eq!(etag_for(ETag::Sha1, b"abc", None), Some(String::from("\"a9993e364706816aba3e25717850c26c9cd0d89d\"")));
+        assert_eq!(etag_for(ETag::LastModified, b"abc", Some(UNIX_EPOCH + ::std::time::Duration::from_millis(1500))), Some(String::from("\"1500\"")));
+    }
+}
@@ -1,14 +1,62 @@
 pub extern crate hyper;
 pub extern crate futures;
+pub extern crate serde_json;
 extern crate percent_encoding;
 extern crate sha1;
+extern crate bytes;
+extern crate flate2;
+extern crate zip;
+extern crate tar;
+extern crate tokio_timer;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate base64;
+extern crate bcrypt;
+extern crate futures_cpupool;
+extern crate sha2;
+extern crate hmac;
+extern crate ring;
+extern crate rand;
+#[macro_use]
+extern crate log;
 use futures::future::Future;
 use hyper::server::{Request, Response};
 use hyper::Error;
 
 
 
-pub type HyperResult = Box<Future<Item = Response, Error = Error>>;
+pub type HyperResult = Box<dyn Future<Item = Response, Error = Error>>;
 pub type Callback = fn(Request) -> HyperResult;
 pub mod pony;
-pub mod pony_builder;
\ No newline at end of file
+pub mod pony_builder;
+pub mod autoindex;
+pub mod static_mount;
+pub mod file_cache;
+pub mod embed;
+pub mod source;
+pub mod archive;
+pub mod error_page;
+pub mod error;
+pub mod problem;
+pub mod access_log;
+pub mod metrics;
+pub mod request_id;
+pub mod timeout;
+pub mod server;
+pub mod rate_limit;
+pub mod body_limit;
+pub mod scope;
+pub mod basic_auth;
+pub mod jwt;
+pub mod csrf;
+pub mod security_headers;
+pub mod websocket;
+mod content_type;
+mod escape;
+mod safe_path;
+mod compare;
+mod sha256;
+mod rsa;
+mod random;
+#[cfg(test)]
+mod test_support;
\ No newline at end of file
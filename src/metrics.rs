@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::{Method, StatusCode};
+
+///The upper bounds, in seconds, of the latency histogram buckets
+pub const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+///What handled a request, static files are grouped
+/// by the prefix of the mount that served them
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RouteLabel {
+    Route(String),
+    Static(String),
+    Unmatched,
+}
+
+impl RouteLabel {
+    fn labels(&self) -> String {
+        let (kind, route) = match *self {
+            RouteLabel::Route(ref path) => ("route", path.as_str()),
+            RouteLabel::Static(ref prefix) => ("static", prefix.as_str()),
+            RouteLabel::Unmatched => ("unmatched", ""),
+        };
+        format!("kind=\"{}\",route=\"{}\"", kind, escape_label(route))
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; 11],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0;
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    requests: BTreeMap<(RouteLabel, String, u16), u64>,
+    statuses: BTreeMap<u16, u64>,
+    latencies: BTreeMap<RouteLabel, Histogram>,
+    in_flight: i64,
+}
+
+///Request counters and latency histograms, shared by every
+/// `Pony` a builder creates
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+    ///Count a request as in flight until the returned
+    /// timer is finished or dropped
+    pub fn start(metrics: &Arc<Metrics>, label: RouteLabel, method: Method) -> RequestTimer {
+        if let Ok(mut counters) = metrics.counters.lock() {
+            counters.in_flight += 1;
+        }
+        RequestTimer {
+            metrics: metrics.clone(),
+            label,
+            method,
+            start: Instant::now(),
+        }
+    }
+    ///Render every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        let counters = match self.counters.lock() {
+            Ok(counters) => counters,
+            Err(_) => return String::new(),
+        };
+        let mut out = String::new();
+        out += "# HELP pony_requests_total Requests handled by route, method and status\n";
+        out += "# TYPE pony_requests_total counter\n";
+        for (&(ref label, ref method, status), count) in counters.requests.iter() {
+            out += &format!("pony_requests_total{{{},method=\"{}\",status=\"{}\"}} {}\n", label.labels(), method, status, count);
+        }
+        out += "# HELP pony_responses_total Responses sent by status\n";
+        out += "# TYPE pony_responses_total counter\n";
+        for (status, count) in counters.statuses.iter() {
+            out += &format!("pony_responses_total{{status=\"{}\"}} {}\n", status, count);
+        }
+        out += "# HELP pony_requests_in_flight Requests currently being handled\n";
+        out += "# TYPE pony_requests_in_flight gauge\n";
+        out += &format!("pony_requests_in_flight {}\n", counters.in_flight);
+        out += "# HELP pony_request_duration_seconds Time taken to respond by route\n";
+        out += "# TYPE pony_request_duration_seconds histogram\n";
+        for (label, histogram) in counters.latencies.iter() {
+            let labels = label.labels();
+            for (count, bound) in histogram.buckets.iter().zip(BUCKETS.iter()) {
+                out += &format!("pony_request_duration_seconds_bucket{{{},le=\"{}\"}} {}\n", labels, bound, count);
+            }
+            out += &format!("pony_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}\n", labels, histogram.count);
+            out += &format!("pony_request_duration_seconds_sum{{{}}} {}\n", labels, histogram.sum);
+            out += &format!("pony_request_duration_seconds_count{{{}}} {}\n", labels, histogram.count);
+        }
+        out
+    }
+}
+
+///A request that is in flight
+pub struct RequestTimer {
+    metrics: Arc<Metrics>,
+    label: RouteLabel,
+    method: Method,
+    start: Instant,
+}
+
+impl RequestTimer {
+    ///Record the response's status and how long it took,
+    /// requests that failed without a response count as a 500
+    pub fn finish(self, status: Option<StatusCode>) {
+        let status = u16::from(status.unwrap_or(StatusCode::InternalServerError));
+        let elapsed = self.start.elapsed();
+        if let Ok(mut counters) = self.metrics.counters.lock() {
+            *counters.requests.entry((self.label.clone(), self.method.to_string(), status)).or_insert(0) += 1;
+            *counters.statuses.entry(status).or_insert(0) += 1;
+            counters.latencies.entry(self.label.clone()).or_default().observe(elapsed);
+        }
+    }
+}
+
+impl Drop for RequestTimer {
+    fn drop(&mut self) {
+        if let Ok(mut counters) = self.metrics.counters.lock() {
+            counters.in_flight -= 1;
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Arc::new(Metrics::new());
+        let timer = Metrics::start(&metrics, RouteLabel::Route(String::from("/get")), Method::Get);
+        let pending = Metrics::start(&metrics, RouteLabel::Static(String::from("/")), Method::Get);
+        timer.finish(Some(StatusCode::Ok));
+        let text = metrics.render();
+        assert!(text.contains("pony_requests_total{kind=\"route\",route=\"/get\",method=\"GET\",status=\"200\"} 1\n"));
+        assert!(text.contains("pony_responses_total{status=\"200\"} 1\n"));
+        assert!(text.contains("pony_requests_in_flight 1\n"));
+        assert!(text.contains("pony_request_duration_seconds_bucket{kind=\"route\",route=\"/get\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("pony_request_duration_seconds_count{kind=\"route\",route=\"/get\"} 1\n"));
+        pending.finish(None);
+        let text = metrics.render();
+        assert!(text.contains("pony_requests_in_flight 0\n"));
+        assert!(text.contains("pony_requests_total{kind=\"static\",route=\"/\",method=\"GET\",status=\"500\"} 1\n"));
+    }
+
+    #[test]
+    fn histogram() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(20));
+        assert_eq!(histogram.buckets[0], 0);
+        assert_eq!(histogram.buckets[2], 1);
+        assert_eq!(histogram.buckets[10], 1);
+        assert_eq!(histogram.count, 2);
+    }
+
+    #[test]
+    fn escaped() {
+        assert_eq!(RouteLabel::Route(String::from("/a\"b")).labels(), "kind=\"route\",route=\"/a\\\"b\"");
+    }
+}
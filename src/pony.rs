@@ -1,178 +1,334 @@
 use std::collections::{HashMap,HashSet};
-use std::fs::{File};
-use std::io::{BufReader, Read};
-use std::path::PathBuf;
+use std::io;
+use std::net::SocketAddr;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use futures::Future;
 use futures::future::ok;
 
-use hyper::{Get, Post, Put, Delete, StatusCode, Error};
+use hyper::{Chunk, Get, Post, Put, Delete, StatusCode, Error};
 use hyper::server::{Service, Request, Response};
-use hyper::header::{ContentLength, ContentEncoding, Encoding, Headers,};
+use hyper::header::{CacheControl, CacheDirective, ContentLength, ContentEncoding, ContentType, Encoding, Headers,};
 
 use percent_encoding::{percent_decode};
 
-use sha1::{Sha1, Digest};
-
-use super::Callback;
+use super::problem::{ErrorHook, Handler, PanicHook};
+use super::access_log::{self, AccessLog, LogEntry, PendingLog, SentBody};
+use super::metrics::{Metrics, RouteLabel};
+use super::request_id::{self, RequestIdPolicy};
+use super::timeout::Timeouts;
+use super::rate_limit::RateLimits;
+use super::body_limit;
+use super::basic_auth::{self, BasicAuth, Verdict};
+use super::jwt::{self, JwtAuth};
+use super::csrf::{self, Csrf};
+use super::security_headers::{self, SecurityHeaders};
+use super::websocket::{self, Upgrade, WebSocketHandler};
+use super::scope::Scoped;
+use super::autoindex;
+use super::safe_path;
+use super::static_mount::StaticMount;
+use super::source::ReadContext;
+use super::file_cache::{CacheStats, FileCache, StaticFile};
+use super::error_page::{prefers_json, ErrorPages};
 ///A set of hyper http settings
+#[derive(Clone)]
 pub struct Pony {
-    pub gets: HashMap<String, Callback>,
-    pub posts: HashMap<String, Callback>,
-    pub puts: HashMap<String, Callback>,
-    pub deletes: HashMap<String, Callback>,
+    pub gets: HashMap<String, Handler>,
+    pub posts: HashMap<String, Handler>,
+    pub puts: HashMap<String, Handler>,
+    pub deletes: HashMap<String, Handler>,
+    pub websockets: HashMap<String, WebSocketHandler>,
+    ///The only origins allowed to open a WebSocket path
+    pub websocket_origins: HashMap<String, Vec<String>>,
+    pub mounts: Vec<StaticMount>,
+    pub error_pages: Arc<ErrorPages>,
+    pub error_hook: Option<ErrorHook>,
+    pub panic_hook: Option<PanicHook>,
+    pub access_log: Option<Arc<AccessLog>>,
+    pub metrics: Option<Arc<Metrics>>,
+    pub metrics_path: String,
+    pub request_ids: Option<RequestIdPolicy>,
+    pub timeouts: Option<Timeouts>,
+    pub rate_limits: Arc<RateLimits>,
+    pub body_limits: Arc<Scoped<u64>>,
+    pub basic_auth: Arc<Scoped<BasicAuth>>,
+    pub jwt_auth: Arc<Scoped<JwtAuth>>,
+    pub csrf: Option<Arc<Csrf>>,
+    pub security_headers: Arc<Scoped<SecurityHeaders>>,
+    ///The client's address when the connection was
+    /// accepted by `PonyBuilder::serve`
+    pub remote_addr: Option<SocketAddr>,
+    ///If this connection was accepted by `PonyBuilder::serve`,
+    /// the only place a WebSocket handshake can be finished
+    pub upgrades: bool,
+    ///A WebSocket handshake answered on this connection,
+    /// taken by `PonyBuilder::serve` to start its handler
+    pub upgrade: Arc<Mutex<Option<Upgrade>>>,
+    pub known_extensions: HashSet<String>,
+    pub static_logging: bool,
+    pub file_cache: Option<Arc<Mutex<FileCache>>>,
+    #[deprecated(note = "the root directory is now the `/` entry in `mounts`")]
     pub static_path: String,
+    #[deprecated(note = "the root directory is now the `/` entry in `mounts`")]
     pub static_enabled: bool,
+    #[deprecated(note = "not found pages are now kept in `error_pages`")]
     pub not_found_path: String,
+    #[deprecated(note = "not found pages are now kept in `error_pages`")]
     pub custom_not_found: bool,
-    pub known_extensions: HashSet<String>,
-    pub static_logging: bool,
+    #[deprecated(note = "use `gzip` on the `/` entry in `mounts`")]
     pub use_gzip: bool,
+    #[deprecated(note = "use `etag` on the `/` entry in `mounts`")]
     pub etag: ETag,
 }
-#[derive(Clone)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
 pub enum ETag {
+    #[default]
     None,
     LastModified,
     Sha1,
 }
 
-impl Default for ETag {
-    fn default() -> Self {
-        ETag::None
-    }
-}
-
 impl Copy for ETag {}
 
+///How symbolic links under a static root are handled
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SymlinkPolicy {
+    ///Follow any link, even if it points outside of the root
+    Follow,
+    ///Follow links only when they resolve to a path inside of the root
+    #[default]
+    WithinRoot,
+    ///Refuse to serve anything reached through a link
+    Deny,
+}
+
 impl Pony {
     ///Try to perform a get request, if path is not found in
     /// this instance's gets HashMap and static files are enabled
-    /// it will attempt to find a static file.
+    /// it will attempt to find a static file in the mount with
+    /// the longest matching prefix.
     /// note this will attempt to find index.html if no file extention
     /// exists on request
     fn get(&self, req: Request) -> super::HyperResult {
         match self.gets.get(req.path()) {
-            Some(cb) => {
-                cb(req)
+            Some(handler) => {
+                self.dispatch(handler, req)
             },
             None => {
-                if self.static_enabled {
-                    let path = if let Ok(p) = percent_decode(req.path().as_bytes()).decode_utf8() {
-                        p
-                    } else {
-                        return self.not_found()
-                    };
-                    if self.static_logging {
-                        println!("GET: {:?}", &path);
-                    }
-                    self.static_file(&path)
+                if self.mounts.is_empty() {
+                    return self.error(StatusCode::NotFound, req.headers())
+                }
+                let path = if let Ok(p) = percent_decode(req.path().as_bytes()).decode_utf8() {
+                    p
                 } else {
-                    self.not_found()
+                    return self.error(StatusCode::NotFound, req.headers())
+                };
+                match self.find_mount(&path) {
+                    Some((mount, relative)) => {
+                        if self.static_logging {
+                            match self.request_id(req.headers()) {
+                                Some(id) => println!("GET: {:?} {}", &path, id),
+                                None => println!("GET: {:?}", &path),
+                            }
+                        }
+                        self.static_file(mount, &path, &relative, req.headers())
+                    },
+                    None => self.error(StatusCode::NotFound, req.headers()),
                 }
             },
         }
     }
+    ///Find the mount with the longest prefix matching a decoded path
+    /// mounts are sorted longest prefix first by the builder
+    fn find_mount(&self, path: &str) -> Option<(&StaticMount, String)> {
+        self.mounts.iter()
+            .filter_map(|m| m.strip(path).map(|relative| (m, relative)))
+            .next()
+    }
     ///Fallback when any get request's path doesn't exist
-    /// in this instance's gets HashMap
-    fn static_file(&self, path: &str) -> super::HyperResult {
-        let mut incoming = String::from(path);
-        if incoming.ends_with('/') {
-            incoming += "index.html";
-        } else if !self.check_for_known_ext(&incoming) {
-            incoming += "/index.html";
-        }
-
-        if self.static_path.ends_with('/') && incoming.starts_with('/') {
-            incoming.remove(0);
-        }
-        let static_path = PathBuf::from(format!("{}{}{}", self.static_path, incoming, if self.use_gzip {
-            ".gz"
-        } else {
-            ""
-        }));
-        let mut headers = Headers::new();
-        if path.ends_with(".wasm") {
-            headers.append_raw("Content-Type", "application/wasm");
-        }
-
-
-        let contents = if self.use_gzip {
-            match Self::read_file(&static_path) {
-                Ok(content) => {
-                    headers.set(ContentEncoding(vec![Encoding::Gzip]));
-                    Ok(content)
-                },
-                Err(_) => Self::read_file(&static_path)
-            }
+    /// in this instance's gets HashMap, url_path is the full
+    /// decoded request path and path is the remainder after
+    /// the mount's prefix
+    fn static_file(&self, mount: &StaticMount, url_path: &str, path: &str, req_headers: &Headers) -> super::HyperResult {
+        let segments = match safe_path::segments(path, mount.dotfiles) {
+            Ok(s) => s,
+            Err(status) => return self.error(status, req_headers),
+        };
+        let dir = segments.join("/");
+        let is_dir = path.ends_with('/') || !self.check_for_known_ext(path);
+        let relative = if !is_dir {
+            dir.clone()
+        } else if dir.is_empty() {
+            String::from("index.html")
         } else {
-            Self::read_file(&static_path)
+            format!("{}/index.html", dir)
         };
-
-        match contents {
-            Ok(c) => {
-                match self.etag {
-                    ETag::LastModified => {
-                        if let Ok(md) = ::std::fs::metadata(path){
-                            if let Ok(t) = md.modified() {
-                                if let Ok(d) = t.duration_since(::std::time::SystemTime::UNIX_EPOCH) {
-                                    headers.append_raw("ETag", format!("{}", (d.as_secs() * 1000) + d.subsec_millis() as u64));
-                                }
-                            }
-                        }
-                    },
-                    ETag::Sha1 => {
-                        let mut sh = Sha1::default();
-                        sh.input(&c);
-                        let res = sh.result();
-                        let mut etag = String::new();
-                        for i in res.into_iter() {
-                            etag.push(i as char);
-                        }
-                        headers.append_raw("ETag", etag);
-                    },
-                    _ => (),
+        match self.read_layered(mount, &relative) {
+            Ok((file, gzipped)) => {
+                let mut headers = Headers::new();
+                headers.set_raw("Content-Type", file.content_type);
+                if gzipped {
+                    headers.set(ContentEncoding(vec![Encoding::Gzip]));
+                }
+                if let Some(etag) = file.etag {
+                    headers.set_raw("ETag", etag);
+                }
+                if let Some(max_age) = mount.max_age {
+                    headers.set(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(max_age)]));
                 }
-                headers.set(ContentLength(c.len() as u64));
+                headers.set(ContentLength(file.contents.len() as u64));
                 Box::new(
                     ok(
                         Response::new()
                             .with_headers(headers)
-                            .with_body(c)
+                            .with_body(Chunk::from(file.contents))
                     )
                 )
             },
-            Err(_) => self.not_found()
+            Err(StatusCode::NotFound) => {
+                if mount.autoindex && is_dir && self.is_layered_dir(mount, &dir) {
+                    self.directory_listing(mount, url_path, &dir, req_headers)
+                } else if mount.spa_fallback && is_dir && path != "/" && !has_extension(path) {
+                    self.static_file(mount, &mount.prefix, "/", req_headers)
+                } else {
+                    self.error(StatusCode::NotFound, req_headers)
+                }
+            },
+            Err(status) => self.error(status, req_headers),
         }
     }
-
-    //attempt to read a file
-    fn read_file(path: &PathBuf) -> Result<Vec<u8>, String> {
-        let file = if let Ok(f) = File::open(path) {
-            f
+    ///Fallback when a directory has no index.html and
+    /// autoindex is enabled, the listing will be json if
+    /// the client accepts application/json otherwise html.
+    /// For layered mounts the listing is the union of every
+    /// layer, the first layer wins for duplicate names
+    fn directory_listing(&self, mount: &StaticMount, path: &str, dir: &str, req_headers: &Headers) -> super::HyperResult {
+        let hide_dotfiles = mount.autoindex_hide_dotfiles || !mount.dotfiles;
+        let cx = self.read_context(mount);
+        let mut entries: Vec<autoindex::Entry> = vec!();
+        for layer in &mount.layers {
+            let listing = match layer.list(dir, &cx) {
+                Ok(listing) => listing,
+                Err(e) => match status_for(&e) {
+                    StatusCode::NotFound => continue,
+                    status => return self.error(status, req_headers),
+                },
+            };
+            for entry in listing {
+                if hide_dotfiles && entry.name.starts_with('.') {
+                    continue;
+                }
+                if !entries.iter().any(|e| e.name == entry.name) {
+                    entries.push(entry);
+                }
+            }
+        }
+        autoindex::sort_entries(&mut entries);
+        let (body, content_type) = if prefers_json(req_headers) {
+            (autoindex::render_json(path, &entries), ContentType::json())
         } else {
-            return Err(String::from("File not found"))
+            (autoindex::render_html(path, &entries), ContentType::html())
         };
-        let mut reader = BufReader::new(file);
-        let mut contents: Vec<u8> = vec!();
-        if let Ok(_) = reader.read_to_end(&mut contents) {
-            Ok(contents)
-        } else {
-            return Err(String::from("File unreadable"))
+        Box::new(
+            ok(
+                Response::new()
+                    .with_header(ContentLength(body.len() as u64))
+                    .with_header(content_type)
+                    .with_body(body)
+            )
+        )
+    }
+    ///Search each of a mount's layers in order for a file,
+    /// when gzip is enabled the .gz version is tried before
+    /// the file itself in each layer.
+    /// Returns the file and if it was the .gz version
+    fn read_layered(&self, mount: &StaticMount, relative: &str) -> Result<(StaticFile, bool), StatusCode> {
+        let cx = self.read_context(mount);
+        for layer in &mount.layers {
+            if mount.gzip {
+                match layer.open(relative, true, &cx) {
+                    Ok(file) => return Ok((file, true)),
+                    Err(e) => match status_for(&e) {
+                        StatusCode::NotFound => (),
+                        status => return Err(status),
+                    },
+                }
+            }
+            match layer.open(relative, false, &cx) {
+                Ok(file) => return Ok((file, false)),
+                Err(e) => match status_for(&e) {
+                    StatusCode::NotFound => (),
+                    status => return Err(status),
+                },
+            }
+        }
+        Err(StatusCode::NotFound)
+    }
+    ///Check if any of a mount's layers has a directory
+    /// at the relative path
+    fn is_layered_dir(&self, mount: &StaticMount, dir: &str) -> bool {
+        let cx = self.read_context(mount);
+        mount.layers.iter().any(|layer| layer.metadata(dir, &cx).map(|md| md.is_dir).unwrap_or(false))
+    }
+    ///The settings used to read files from a mount's layers
+    fn read_context<'a>(&'a self, mount: &StaticMount) -> ReadContext<'a> {
+        ReadContext {
+            etag: mount.etag,
+            symlinks: mount.symlinks,
+            cache: self.file_cache.as_deref(),
         }
     }
+    ///The current counters for the file cache
+    /// if one is enabled
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.file_cache.as_ref()
+            .and_then(|cache| cache.lock().ok().map(|cache| cache.stats()))
+    }
+
     ///Check for a path's extention to be in our list of
     /// known extensions
     fn check_for_known_ext(&self, path: &str) -> bool {
         if path.ends_with("/") {
             return false;
         }
-        let ext = path.split('.').last().expect("failed to get last item in path");
+        let ext = path.split('.').next_back().expect("failed to get last item in path");
         self.known_extensions.contains(ext)
     }
 
 
 }
 
+///The status to respond with when a `StaticSource` fails
+fn status_for(e: &io::Error) -> StatusCode {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => StatusCode::Forbidden,
+        _ => StatusCode::NotFound,
+    }
+}
+
+///The message passed to `panic!` if it was a string
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("Box<Any>")
+    }
+}
+
+///Check if the last segment of a path has a file extension
+fn has_extension(path: &str) -> bool {
+    match path.rsplit('/').next() {
+        Some(last) => last.contains('.'),
+        None => false,
+    }
+}
+
 impl Service for Pony {
     type Request = Request;
     type Response = Response;
@@ -180,70 +336,381 @@ impl Service for Pony {
     type Future = super::HyperResult;
     ///This is used by hyper to respond to any requests
     fn call(&self, req: Request) -> Self::Future {
-        match req.method() {
-            &Get => {
+        Box::new(self.respond(req).map(|(res, log)| {
+            if let Some((log, entry)) = log {
+                access_log::log_now(&log, entry, &res);
+            }
+            res
+        }))
+    }
+}
+
+///A response whose access log entry is written once
+/// its body has been sent
+pub type SentResult = Box<dyn Future<Item = Response<SentBody>, Error = Error>>;
+
+impl Pony {
+    ///Answer a request like `call`, but log it with the bytes
+    /// of the body really sent, `PonyBuilder::serve` uses this.
+    /// `sent` runs once the whole response has been sent
+    pub fn call_counted(&self, req: Request, sent: Box<dyn FnOnce()>) -> SentResult {
+        Box::new(self.respond(req).map(|(res, log)| access_log::log_sent(log, res, sent)))
+    }
+
+    ///The response to a request and its access log entry,
+    /// which the caller writes once it knows the bytes sent
+    fn respond(&self, req: Request) -> Box<dyn Future<Item = (Response, Option<PendingLog>), Error = Error>> {
+        let mut req = req;
+        //the headers handlers read what Pony found out from
+        //are never taken from the client
+        let internal = [basic_auth::USER_HEADER, jwt::CLAIMS_HEADER, csrf::ISSUED_HEADER, security_headers::NONCE_HEADER];
+        for name in &internal {
+            req.headers_mut().remove_raw(name);
+        }
+        let request_id = self.request_ids.map(|policy| request_id::assign(&mut req, policy));
+        if self.access_log.is_none() && self.metrics.is_none() && request_id.is_none() {
+            return Box::new(self.secured(req).map(|res| (res, None)))
+        }
+        let timer = self.metrics.as_ref()
+            .map(|metrics| Metrics::start(metrics, self.route_label(&req), req.method().clone()));
+        let log = self.access_log.as_ref()
+            .map(|log| {
+                let mut entry = LogEntry::new(&req);
+                entry.remote_addr = entry.remote_addr.or(self.remote_addr);
+                entry.request_id = request_id.clone();
+                (log.clone(), entry)
+            });
+        let start = Instant::now();
+        Box::new(
+            self.secured(req).then(move |res| {
+                let res = res.map(|mut res| {
+                    if let Some(id) = request_id {
+                        res.headers_mut().set_raw(request_id::HEADER, id);
+                    }
+                    res
+                });
+                let status = res.as_ref().ok().map(|res| res.status());
+                if let Some(timer) = timer {
+                    timer.finish(status);
+                }
+                let log = log.map(|(log, mut entry)| {
+                    entry.duration = start.elapsed();
+                    (log, entry)
+                });
+                match res {
+                    Ok(res) => Ok((res, log)),
+                    Err(e) => {
+                        if let Some((log, entry)) = log {
+                            log.log(&entry);
+                        }
+                        Err(e)
+                    },
+                }
+            })
+        )
+    }
+}
+
+impl Pony {
+    ///Add the security headers for a request's scope to
+    /// whatever response it gets, including error pages
+    fn secured(&self, req: Request) -> super::HyperResult {
+        if self.security_headers.is_empty() {
+            return self.route(req)
+        }
+        let mut req = req;
+        let headers = match self.security_headers.find(req.method(), req.path()) {
+            Some((_, headers)) => headers.clone(),
+            None => return self.route(req),
+        };
+        let nonce = if headers.uses_nonce() {
+            let nonce = security_headers::generate_nonce();
+            req.headers_mut().set_raw(security_headers::NONCE_HEADER, nonce.clone());
+            Some(nonce)
+        } else {
+            None
+        };
+        Box::new(self.route(req).map(move |mut res| {
+            headers.apply(&mut res, nonce.as_deref());
+            res
+        }))
+    }
+    ///Find the response for a request by method and path
+    fn route(&self, req: Request) -> super::HyperResult {
+        if self.rate_limits.is_empty() || self.is_metrics_request(&req) {
+            return self.authorized(req)
+        }
+        match self.rate_limits.check(&req, self.remote_addr) {
+            Some(decision) if !decision.allowed => {
+                Box::new(ok(decision.apply(self.error_pages.response(StatusCode::TooManyRequests, req.headers()))))
+            },
+            Some(decision) => Box::new(self.authorized(req).map(move |res| decision.apply(res))),
+            None => self.authorized(req),
+        }
+    }
+    ///Check a request's credentials before it is handled, the
+    /// authenticated user or token claims are passed to handlers
+    /// in a header along with the CSRF token to render
+    fn authorized(&self, req: Request) -> super::HyperResult {
+        let mut req = req;
+        let auth = match self.basic_auth.find(req.method(), req.path()) {
+            Some((_, auth)) => auth,
+            None => return self.bearer_authorized(req),
+        };
+        let unauthorized = {
+            let (error_pages, challenge) = (self.error_pages.clone(), auth.challenge());
+            move |headers: &Headers| {
+                let mut res = error_pages.response(StatusCode::Unauthorized, headers);
+                res.headers_mut().set_raw("WWW-Authenticate", challenge);
+                res
+            }
+        };
+        match auth.authenticate(req.headers()) {
+            Verdict::Now(Some(user)) => {
+                req.headers_mut().set_raw(basic_auth::USER_HEADER, user);
+                self.bearer_authorized(req)
+            },
+            Verdict::Now(None) => Box::new(ok(unauthorized(req.headers()))),
+            Verdict::Pending(verdict) => {
+                //only a first login or a wrong password waits
+                //for bcrypt, so cloning here is rare
+                let pony = self.clone();
+                Box::new(verdict.then(move |user| -> super::HyperResult {
+                    match user {
+                        Ok(Some(user)) => {
+                            req.headers_mut().set_raw(basic_auth::USER_HEADER, user);
+                            pony.bearer_authorized(req)
+                        },
+                        _ => Box::new(ok(unauthorized(req.headers()))),
+                    }
+                }))
+            },
+        }
+    }
+    ///The rest of `authorized` once any Basic login has passed
+    fn bearer_authorized(&self, req: Request) -> super::HyperResult {
+        let mut req = req;
+        if let Some((_, auth)) = self.jwt_auth.find(req.method(), req.path()) {
+            match auth.authenticate(req.headers()) {
+                Ok(claims) => req.headers_mut().set_raw(jwt::CLAIMS_HEADER, claims.payload().to_string()),
+                Err(e) => {
+                    let mut res = self.error_pages.response(StatusCode::Unauthorized, req.headers());
+                    res.headers_mut().set_raw("WWW-Authenticate", auth.challenge(&e));
+                    return Box::new(ok(res))
+                },
+            }
+        }
+        if self.is_metrics_request(&req) {
+            return self.render_metrics()
+        }
+        let csrf = match self.csrf {
+            Some(ref csrf) => csrf.clone(),
+            None => return self.handle(req),
+        };
+        let (token, new) = csrf.issue(req.headers());
+        req.headers_mut().set_raw(csrf::ISSUED_HEADER, token.clone());
+        if !new {
+            return self.handle(req)
+        }
+        Box::new(self.handle(req).map(move |mut res| {
+            csrf.set_cookie(&mut res, &token);
+            res
+        }))
+    }
+    fn handle(&self, req: Request) -> super::HyperResult {
+        match *req.method() {
+            Get if self.websockets.contains_key(req.path()) => {
+                self.upgrade(req)
+            },
+            Get => {
                 self.get(req)
             },
-            &Post => {
+            Post => {
                 match self.posts.get(req.path()) {
-                    Some(cb) => cb(req),
-                    None => self.not_found(),
+                    Some(handler) => self.dispatch(handler, req),
+                    None => self.error(StatusCode::NotFound, req.headers()),
                 }
             },
-            &Put => {
+            Put => {
                 match self.puts.get(req.path()) {
-                    Some(cb) => cb(req),
-                    None => self.not_found(),
+                    Some(handler) => self.dispatch(handler, req),
+                    None => self.error(StatusCode::NotFound, req.headers()),
                 }
             },
-            &Delete => {
+            Delete => {
                 match self.deletes.get(req.path()) {
-                    Some(cb) => cb(req),
-                    None => self.not_found(),
+                    Some(handler) => self.dispatch(handler, req),
+                    None => self.error(StatusCode::NotFound, req.headers()),
                 }
             }
             _ => {
-                self.not_found()
+                self.error(StatusCode::NotFound, req.headers())
             }
         }
     }
 }
 
 impl Pony {
-    ///This will return the default 404 text or
-    /// a custom 404 .html file if one was provided
-    fn not_found(&self) -> super::HyperResult {
-        if self.custom_not_found {
-            let path = PathBuf::from(&self.not_found_path);
-            let file = if let Ok(f) = File::open(path) {
-                f
-            } else {
-                panic!(format!("Unable to find file 404 file: {:?}", self.not_found_path));
-            };
-            let mut reader = BufReader::new(file);
-            let mut bytes: Vec<u8> = vec!();
-            if let Ok(size) = reader.read_to_end(&mut bytes) {
-                Box::new(
-                    ok(
-                        Response::new()
-                        .with_status(StatusCode::Ok)
-                        .with_header(ContentLength(size as u64))
-                        .with_body(bytes)
-                    )
-                )
-            } else {
-                Pony::default_not_found()
-            }
-        } else {
-            Pony::default_not_found()
+    ///What will handle a request, for grouping metrics
+    fn route_label(&self, req: &Request) -> RouteLabel {
+        let path = req.path();
+        let routes = match *req.method() {
+            Get => &self.gets,
+            Post => &self.posts,
+            Put => &self.puts,
+            Delete => &self.deletes,
+            _ => return RouteLabel::Unmatched,
+        };
+        if routes.contains_key(path) || self.is_metrics_request(req) || (*req.method() == Get && self.websockets.contains_key(path)) {
+            return RouteLabel::Route(path.to_string())
         }
+        if *req.method() != Get {
+            return RouteLabel::Unmatched
+        }
+        let decoded = percent_decode(path.as_bytes()).decode_utf8_lossy();
+        match self.find_mount(&decoded) {
+            Some((mount, _)) => RouteLabel::Static(mount.prefix.clone()),
+            None => RouteLabel::Unmatched,
+        }
+    }
+    fn is_metrics_request(&self, req: &Request) -> bool {
+        self.metrics.is_some() && *req.method() == Get && req.path() == self.metrics_path
     }
-    ///The default 404
-    fn default_not_found() -> super::HyperResult {
+    ///The Prometheus text exposition of this instance's metrics
+    fn render_metrics(&self) -> super::HyperResult {
+        let body = self.metrics.as_ref().map(|m| m.render()).unwrap_or_default();
         Box::new(
             ok(
                 Response::new()
-                    .with_status(StatusCode::NotFound)
+                    .with_header(ContentLength(body.len() as u64))
+                    .with_header(ContentType("text/plain; version=0.0.4".parse().expect("invalid content type")))
+                    .with_body(body)
+            )
+        )
+    }
+    ///Run a route's handler, a panic while creating or polling
+    /// its response is reported to the panic hook and turned into
+    /// a 500 using the configured error page. A handler that runs
+    /// past its timeout is dropped and the timeout status is sent.
+    /// A body over the route's size limit gets a 413, before the
+    /// handler runs when its `Content-Length` is too large
+    fn dispatch(&self, handler: &Handler, req: Request) -> super::HyperResult {
+        let method = req.method().clone();
+        let path = req.path().to_string();
+        let headers = req.headers().clone();
+        let (req, pump) = match self.body_limits.find(&method, &path).map(|(_, max)| *max) {
+            Some(max) if body_limit::declared_too_large(&req, max) => {
+                return self.error(StatusCode::PayloadTooLarge, &headers)
+            },
+            Some(max) => {
+                let (req, pump) = body_limit::limit(req, max);
+                (req, Some(pump))
+            },
+            None => (req, None),
+        };
+        let limit = self.timeouts.as_ref()
+            .and_then(|timeouts| timeouts.limits.find(&method, &path).map(|(_, limit)| (timeouts, *limit)));
+        let request_id = self.request_id(&headers).map(|id| format!(" ({})", id)).unwrap_or_default();
+        let on_timeout = {
+            let (method, path, headers, request_id) = (method.clone(), path.clone(), headers.clone(), request_id.clone());
+            let error_pages = self.error_pages.clone();
+            let status = self.timeouts.as_ref().map(|t| t.status).unwrap_or(StatusCode::ServiceUnavailable);
+            move |limit: Duration| move || {
+                warn!("handler for {} {} timed out after {:?}{}", method, path, limit, request_id);
+                error_pages.response(status, &headers)
+            }
+        };
+        let on_exceeded = {
+            let (error_pages, headers) = (self.error_pages.clone(), headers.clone());
+            move || error_pages.response(StatusCode::PayloadTooLarge, &headers)
+        };
+        let error_pages = self.error_pages.clone();
+        let panic_hook = self.panic_hook;
+        let on_panic = move |payload: Box<dyn Any + Send>| {
+            let msg = panic_message(&*payload);
+            match panic_hook {
+                Some(hook) => hook(&method, &path, &msg),
+                None => error!("handler for {} {} panicked: {}{}", method, path, msg, request_id),
+            }
+            error_pages.response(StatusCode::InternalServerError, &headers)
+        };
+        let fut = match panic::catch_unwind(AssertUnwindSafe(|| self.call_handler(handler, req))) {
+            Ok(fut) => fut,
+            Err(payload) => return Box::new(ok(on_panic(payload))),
+        };
+        let fut: super::HyperResult = Box::new(
+            AssertUnwindSafe(fut).catch_unwind()
+                .then(move |res| match res {
+                    Ok(res) => res,
+                    Err(payload) => Ok(on_panic(payload)),
+                })
+        );
+        let fut = match pump {
+            Some(pump) => body_limit::guard(fut, pump, on_exceeded),
+            None => fut,
+        };
+        match limit {
+            Some((timeouts, limit)) => timeouts.apply(fut, limit, on_timeout(limit)),
+            None => fut,
+        }
+    }
+    ///Call a handler once any CSRF token the request has to
+    /// send back has been checked, a 403 is sent when it's wrong
+    fn call_handler(&self, handler: &Handler, req: Request) -> super::HyperResult {
+        match self.csrf {
+            Some(ref csrf) if csrf.applies(req.method(), req.path()) => {
+                let (handler, hook) = (handler.clone(), self.error_hook);
+                let (error_pages, headers) = (self.error_pages.clone(), req.headers().clone());
+                Box::new(csrf.verify(req).and_then(move |req| match req {
+                    Ok(req) => handler.call(req, hook),
+                    Err(status) => Box::new(ok(error_pages.response(status, &headers))),
+                }))
+            },
+            _ => handler.call(req, self.error_hook),
+        }
+    }
+    ///Answer a WebSocket handshake with a 101, the connection
+    /// is handed to the route's handler once it has been sent
+    fn upgrade(&self, req: Request) -> super::HyperResult {
+        if !self.upgrades {
+            return self.error(StatusCode::NotImplemented, req.headers())
+        }
+        let accept = match websocket::accept(&req) {
+            Ok(accept) => accept,
+            Err(StatusCode::UpgradeRequired) => {
+                let mut res = self.error_pages.response(StatusCode::UpgradeRequired, req.headers());
+                res.headers_mut().set_raw("Upgrade", "websocket");
+                res.headers_mut().set_raw("Sec-WebSocket-Version", "13");
+                return Box::new(ok(res))
+            },
+            Err(status) => return self.error(status, req.headers()),
+        };
+        if let Some(origins) = self.websocket_origins.get(req.path()) {
+            if !websocket::origin_allowed(&req, origins) {
+                return self.error(StatusCode::Forbidden, req.headers())
+            }
+        }
+        let handler = self.websockets[req.path()];
+        if let Ok(mut upgrade) = self.upgrade.lock() {
+            *upgrade = Some(Upgrade { handler, request: req });
+        }
+        let mut res = Response::new().with_status(StatusCode::SwitchingProtocols);
+        res.headers_mut().set_raw("Upgrade", "websocket");
+        res.headers_mut().set_raw("Connection", "Upgrade");
+        res.headers_mut().set_raw("Sec-WebSocket-Accept", accept);
+        Box::new(ok(res))
+    }
+    ///The id Pony assigned a request, never one only
+    /// sent by the client
+    fn request_id<'a>(&self, headers: &'a Headers) -> Option<&'a str> {
+        self.request_ids.and_then(|_| request_id::from_headers(headers))
+    }
+    ///The configured error page for a status, sent
+    /// with that status
+    fn error(&self, status: StatusCode, req_headers: &Headers) -> super::HyperResult {
+        Box::new(
+            ok(
+                self.error_pages.response(status, req_headers)
             )
         )
     }
@@ -253,10 +720,14 @@ impl Pony {
 mod tests {
     use super::*;
     use super::super::pony_builder::PonyBuilder;
+    use super::super::source::MemorySource;
+    use super::super::problem::{FallibleResult, Problem, ResponseError};
     use super::super::HyperResult;
     use hyper::server::Request;
     use hyper::{Method, Uri};
     use std::str::FromStr;
+    use std::fs::File;
+    use std::io::Read;
     use futures::future::ok;
     use std::boxed::Box;
     use futures::{Future, Stream};
@@ -300,6 +771,7 @@ mod tests {
         assert!(d == "DELETE");
     }
 
+    #[allow(clippy::match_ref_pats)]
     fn response(req: Request) -> HyperResult {
         let method = req.method();
         let body = match method {
@@ -337,6 +809,282 @@ mod tests {
         assert!(c == buf);
     }
 
+    fn autoindex_boiler(hide_dotfiles: bool, accept: Option<&str>) -> (StatusCode, String) {
+        let mut pb = PonyBuilder::new();
+        pb.use_static("examples/public")
+            .use_dotfiles()
+            .use_autoindex(hide_dotfiles);
+        let p = pb.done();
+        let mut req = Request::new(Method::Get, Uri::from_str("/files/").unwrap());
+        if let Some(accept) = accept {
+            req.headers_mut().set_raw("Accept", accept);
+        }
+        p.call(req).then(|r| {
+            let res = r.unwrap();
+            let status = res.status();
+            res.body().concat2().map(move |c| (status, String::from_utf8(c.to_vec()).unwrap())).wait()
+        }).wait().unwrap()
+    }
+
+    #[test]
+    fn autoindex_html() {
+        let (status, body) = autoindex_boiler(true, None);
+        assert!(status == StatusCode::Ok);
+        assert!(body.contains("<a href=\"/files/a.txt\">a.txt</a>"));
+        assert!(!body.contains(".hidden"));
+    }
+
+    #[test]
+    fn autoindex_json() {
+        let (_, body) = autoindex_boiler(false, Some("application/json"));
+        assert!(body.starts_with("{\"path\":\"/files/\""));
+        assert!(body.contains("\"name\":\".hidden\""));
+    }
+
+    #[test]
+    fn autoindex_disabled() {
+        let mut pb = PonyBuilder::new();
+        pb.use_static("examples/public");
+        let p = pb.done();
+        let status = p.call(Request::new(Method::Get, Uri::from_str("/files/").unwrap())).wait().unwrap().status();
+        assert!(status == StatusCode::NotFound);
+    }
+
+    fn status_boiler(pb: &PonyBuilder, route: &str) -> StatusCode {
+        let p = pb.done();
+        p.call(Request::new(Method::Get, Uri::from_str(route).unwrap())).wait().unwrap().status()
+    }
+
+    #[test]
+    fn traversal() {
+        let mut pb = PonyBuilder::new();
+        pb.use_static("examples/public/files")
+            .add_known_extension(&["toml", "rs"]);
+        let attempts = [
+            "/../../../Cargo.toml",
+            "/%2e%2e/%2e%2e/%2e%2e/Cargo.toml",
+            "/%2E%2E%2F%2E%2E%2F%2E%2E%2FCargo.toml",
+            "/a.txt/..%2f..%2f..%2f..%2fCargo.toml",
+            "/..%5c..%5c..%5cCargo.toml",
+            "/%2e%2e%5c%2e%2e%5c%2e%2e%5csrc%5clib.rs",
+            "/a.txt%00.html",
+            "//etc/passwd",
+            "/%2fetc%2fpasswd",
+            "/.hidden",
+            "/%2ehidden",
+            "/../public/files/a.txt",
+        ];
+        for attempt in attempts.iter() {
+            let status = status_boiler(&pb, attempt);
+            assert!(status == StatusCode::NotFound || status == StatusCode::Forbidden,
+                    "{} returned {}", attempt, status);
+        }
+        assert!(status_boiler(&pb, "/a.txt") == StatusCode::Ok);
+    }
+
+    #[test]
+    fn dotfiles() {
+        let mut pb = PonyBuilder::new();
+        pb.use_static("examples/public");
+        assert!(status_boiler(&pb, "/files/.hidden") == StatusCode::NotFound);
+        pb.add_known_extension(&["hidden"])
+            .use_dotfiles();
+        assert!(status_boiler(&pb, "/files/.hidden") == StatusCode::Ok);
+    }
+
+    #[test]
+    fn symlinks() {
+        let mut pb = PonyBuilder::new();
+        pb.use_static("examples/public")
+            .add_known_extension(&["toml"]);
+        assert!(status_boiler(&pb, "/links/inside.txt") == StatusCode::Ok);
+        assert!(status_boiler(&pb, "/links/outside.toml") == StatusCode::Forbidden);
+        pb.use_symlink_policy(SymlinkPolicy::Deny);
+        assert!(status_boiler(&pb, "/links/inside.txt") == StatusCode::Forbidden);
+        pb.use_symlink_policy(SymlinkPolicy::Follow);
+        assert!(status_boiler(&pb, "/links/outside.toml") == StatusCode::Ok);
+    }
+
+    #[test]
+    fn spa_fallback() {
+        let mut file = File::open("examples/public/index.html").unwrap();
+        let mut index = String::new();
+        file.read_to_string(&mut index).unwrap();
+        let mut pb = PonyBuilder::new();
+        pb.use_static("examples/public");
+        assert!(status_boiler(&pb, "/users/42") == StatusCode::NotFound);
+        pb.use_spa_fallback();
+        let p = pb.done();
+        let c: String = p.call(Request::new(Method::Get, Uri::from_str("/users/42").unwrap())).then(|r| {
+            r.unwrap().body().concat2().map(|c| String::from_utf8(c.to_vec()).unwrap()).wait()
+        }).wait().unwrap();
+        assert!(c == index);
+        assert!(status_boiler(&pb, "/app.js") == StatusCode::NotFound);
+        assert!(status_boiler(&pb, "/users/42.json") == StatusCode::NotFound);
+    }
+
+    #[test]
+    fn mounts() {
+        let mut pb = PonyBuilder::new();
+        pb.use_static("examples/public")
+            .use_static_mount(StaticMount::new("/assets", "examples/public/files")
+                                .use_max_age(60))
+            .use_static_mount(StaticMount::new("/assets/listing", "examples/public/files")
+                                .use_autoindex(true));
+        let p = pb.done();
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/assets/a.txt").unwrap())).wait().unwrap();
+        assert!(res.status() == StatusCode::Ok);
+        assert!(res.headers().get::<CacheControl>() == Some(&CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(60)])));
+        let body = res.body().concat2().wait().unwrap();
+        assert!(&*body == b"a\n");
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/index.html").unwrap())).wait().unwrap();
+        assert!(res.status() == StatusCode::Ok);
+        assert!(res.headers().get::<CacheControl>().is_none());
+        assert!(status_boiler(&pb, "/assets/index.html") == StatusCode::NotFound);
+        assert!(status_boiler(&pb, "/assets/") == StatusCode::NotFound);
+        assert!(status_boiler(&pb, "/assets/listing") == StatusCode::Ok);
+        assert!(status_boiler(&pb, "/assetsa.txt") == StatusCode::NotFound);
+    }
+
+    #[test]
+    fn layers() {
+        let mut pb = PonyBuilder::new();
+        pb.use_static("examples/public/files")
+            .add_static_layer("examples/public")
+            .use_autoindex(true);
+        let p = pb.done();
+        let body = p.call(Request::new(Method::Get, Uri::from_str("/a.txt").unwrap())).wait().unwrap()
+            .body().concat2().wait().unwrap();
+        assert!(&*body == b"a\n");
+        assert!(status_boiler(&pb, "/404.html") == StatusCode::Ok);
+        assert!(status_boiler(&pb, "/files/a.txt") == StatusCode::Ok);
+        let mut file = File::open("examples/public/index.html").unwrap();
+        let mut index = String::new();
+        file.read_to_string(&mut index).unwrap();
+        let body = p.call(Request::new(Method::Get, Uri::from_str("/").unwrap())).wait().unwrap()
+            .body().concat2().wait().unwrap();
+        assert!(&*body == index.as_bytes());
+    }
+
+    #[test]
+    fn layered_gzip_and_listing() {
+        let mut pb = PonyBuilder::new();
+        pb.use_static_mount(StaticMount::new("/", "examples/public/files")
+                                .add_layer("examples/public/links")
+                                .use_gzip()
+                                .use_autoindex(true));
+        let p = pb.done();
+        let mut req = Request::new(Method::Get, Uri::from_str("/").unwrap());
+        req.headers_mut().set_raw("Accept", "application/json");
+        let body = p.call(req).wait().unwrap().body().concat2().wait().unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("\"name\":\"a.txt\""));
+        assert!(body.contains("\"name\":\"inside.txt\""));
+        let mut pb = PonyBuilder::new();
+        pb.use_static_mount(StaticMount::new("/", "examples/public/files")
+                                .add_layer("examples/public")
+                                .use_gzip());
+        let p = pb.done();
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/").unwrap())).wait().unwrap();
+        assert!(res.headers().get::<ContentEncoding>() == Some(&ContentEncoding(vec![Encoding::Gzip])));
+    }
+
+    #[test]
+    fn file_cache() {
+        let mut pb = PonyBuilder::new();
+        pb.use_static("examples/public")
+            .use_etag(ETag::Sha1)
+            .use_file_cache(1024 * 1024, 1024);
+        let p = pb.done();
+        assert!(p.cache_stats() == Some(CacheStats::default()));
+        for _ in 0..3 {
+            let res = p.call(Request::new(Method::Get, Uri::from_str("/").unwrap())).wait().unwrap();
+            assert!(res.status() == StatusCode::Ok);
+            assert!(res.headers().get_raw("Content-Type").unwrap() == "text/html; charset=utf-8");
+            assert!(res.headers().get_raw("ETag").is_some());
+        }
+        let other = pb.done();
+        other.call(Request::new(Method::Get, Uri::from_str("/404.html").unwrap())).wait().unwrap();
+        let stats = pb.cache_stats().unwrap();
+        assert!(stats.hits == 2, "expected 2 hits found {}", stats.hits);
+        assert!(stats.misses == 2, "expected 2 misses found {}", stats.misses);
+        assert!(stats.entries == 2, "expected 2 entries found {}", stats.entries);
+    }
+
+    #[test]
+    fn embedded() {
+        let mut pb = PonyBuilder::new();
+        pb.use_embedded(&::test_support::BUNDLE)
+            .use_autoindex(true)
+            .use_static_mount(StaticMount::embedded("/gz", &::test_support::BUNDLE).use_gzip());
+        let p = pb.done();
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/").unwrap())).wait().unwrap();
+        assert!(res.headers().get_raw("ETag").is_none());
+        assert!(res.headers().get_raw("Content-Type").unwrap() == "text/html; charset=utf-8");
+        let body = res.body().concat2().wait().unwrap();
+        assert!(&*body == b"<h1>embedded</h1>");
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/gz/index.html").unwrap())).wait().unwrap();
+        assert!(res.headers().get::<ContentEncoding>() == Some(&ContentEncoding(vec![Encoding::Gzip])));
+        let body = res.body().concat2().wait().unwrap();
+        assert!(&*body == b"gzipped");
+        assert!(status_boiler(&pb, "/gz/css/site.css") == StatusCode::Ok);
+        assert!(status_boiler(&pb, "/js/") == StatusCode::Ok);
+        assert!(status_boiler(&pb, "/gz/js/") == StatusCode::NotFound);
+        assert!(status_boiler(&pb, "/missing.js") == StatusCode::NotFound);
+    }
+
+    #[test]
+    fn memory_source() {
+        let mut source = MemorySource::new();
+        source.insert("index.html", "<h1>memory</h1>")
+            .insert("docs/guide.txt", "guide");
+        let mut pb = PonyBuilder::new();
+        pb.use_static_source(Arc::new(source))
+            .use_autoindex(true);
+        let p = pb.done();
+        let body = p.call(Request::new(Method::Get, Uri::from_str("/").unwrap())).wait().unwrap()
+            .body().concat2().wait().unwrap();
+        assert!(&*body == b"<h1>memory</h1>");
+        assert!(status_boiler(&pb, "/docs/guide.txt") == StatusCode::Ok);
+        assert!(status_boiler(&pb, "/docs/") == StatusCode::Ok);
+        assert!(status_boiler(&pb, "/docs/missing.txt") == StatusCode::NotFound);
+    }
+
+    #[test]
+    fn archive() {
+        let path = ::test_support::tar_file("pony.tar");
+        let mut pb = PonyBuilder::new();
+        pb.use_archive(path.to_str().unwrap())
+            .use_static_gzip()
+            .use_etag(ETag::Sha1);
+        let p = pb.done();
+        let req = Request::new(Method::Get, Uri::from_str("/").unwrap());
+        let res = p.call(req).wait().unwrap();
+        assert_eq!(res.headers().get::<ContentEncoding>(), Some(&ContentEncoding(vec![Encoding::Gzip])));
+        assert_eq!(res.headers().get::<ContentType>().map(|c| c.to_string()), Some(String::from("text/html; charset=utf-8")));
+        assert!(res.headers().get_raw("ETag").is_some());
+        assert!(status_boiler(&pb, "/css/site.css") == StatusCode::Ok);
+        assert!(status_boiler(&pb, "/css/missing.css") == StatusCode::NotFound);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn archive_zip() {
+        let path = ::test_support::zip_file("pony.zip");
+        let mut pb = PonyBuilder::new();
+        pb.use_archive(path.to_str().unwrap())
+            .use_autoindex(true);
+        let p = pb.done();
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/").unwrap())).wait().unwrap();
+        assert_eq!(res.headers().get::<ContentType>().map(|c| c.to_string()), Some(String::from("text/html; charset=utf-8")));
+        let body = res.body().concat2().wait().unwrap();
+        assert!(&*body == b"<h1>archive</h1>");
+        assert!(status_boiler(&pb, "/js/app/main.js") == StatusCode::Ok);
+        assert!(status_boiler(&pb, "/css/") == StatusCode::Ok);
+        assert!(status_boiler(&pb, "/missing.html") == StatusCode::NotFound);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn four_oh_four() {
         let mut file = File::open("examples/public/404.html").unwrap();
@@ -350,5 +1098,443 @@ mod tests {
             r.unwrap().body().concat2().map(|c| String::from_utf8(c.to_vec()).unwrap()).wait()
         }).wait().unwrap();
         assert!(c == buf);
+        assert!(status_boiler(&pb, "/junk") == StatusCode::NotFound);
+    }
+
+    #[test]
+    fn error_pages() {
+        let mut pb = PonyBuilder::new();
+        pb.use_static("examples/public")
+            .add_known_extension(&["toml"])
+            .use_error_page(StatusCode::NotFound, "examples/public/404.html")
+            .use_error_page(StatusCode::Forbidden, "examples/public/files/a.txt");
+        let p = pb.done();
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/missing.txt").unwrap())).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(res.headers().get::<ContentType>().map(|c| c.to_string()), Some(String::from("text/html; charset=utf-8")));
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/links/outside.toml").unwrap())).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::Forbidden);
+        let body = res.body().concat2().wait().unwrap();
+        assert!(&*body == b"a\n");
+        let mut req = Request::new(Method::Get, Uri::from_str("/missing.txt").unwrap());
+        req.headers_mut().set_raw("Accept", "application/json");
+        let res = p.call(req).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(res.headers().get::<ContentType>(), Some(&ContentType::json()));
+    }
+
+    fn validate(req: Request) -> FallibleResult<Problem> {
+        Box::new(req.body().concat2()
+            .map_err(|_| Problem::new(StatusCode::BadRequest))
+            .and_then(|body| if body.is_empty() {
+                Err(Problem::new(StatusCode::UnprocessableEntity).with_detail("a body is required"))
+            } else {
+                Ok(Response::new())
+            }))
+    }
+
+    fn teapot(_method: &Method, path: &str, _err: &dyn ResponseError) -> Option<Response> {
+        if path == "/hooked" {
+            Some(Response::new().with_status(StatusCode::ImATeapot))
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn fallible_routes() {
+        let mut pb = PonyBuilder::new();
+        pb.get_fallible("/validate", validate)
+            .post_fallible("/hooked", validate)
+            .use_error_hook(teapot);
+        let p = pb.done();
+        let mut req = Request::new(Method::Get, Uri::from_str("/validate").unwrap());
+        req.set_body("q=1");
+        let res = p.call(req).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/validate").unwrap())).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::UnprocessableEntity);
+        assert_eq!(res.headers().get::<ContentType>().unwrap().to_string(), "application/problem+json");
+        let res = p.call(Request::new(Method::Post, Uri::from_str("/hooked").unwrap())).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::ImATeapot);
+    }
+
+    fn explode(_req: Request) -> HyperResult {
+        panic!("boom")
+    }
+
+    fn explode_later(_req: Request) -> HyperResult {
+        Box::new(::futures::future::lazy(|| -> Result<Response, Error> { panic!("later {}", 1) }))
+    }
+
+    static PANICS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    fn record_panic(method: &Method, path: &str, msg: &str) {
+        PANICS.lock().unwrap().push(format!("{} {} {}", method, path, msg));
+    }
+
+    #[test]
+    fn handler_panics() {
+        let mut pb = PonyBuilder::new();
+        pb.get("/now", explode)
+            .post("/later", explode_later)
+            .use_error_page(StatusCode::InternalServerError, "examples/public/files/a.txt")
+            .use_panic_hook(record_panic);
+        let p = pb.done();
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/now").unwrap())).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+        assert!(&*res.body().concat2().wait().unwrap() == b"a\n");
+        let res = p.call(Request::new(Method::Post, Uri::from_str("/later").unwrap())).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+        let panics = PANICS.lock().unwrap();
+        assert_eq!(*panics, vec![String::from("GET /now boom"), String::from("POST /later later 1")]);
+    }
+
+    #[test]
+    fn access_log() {
+        let path = ::test_support::temp_path("pony-access.log");
+        let mut pb = PonyBuilder::new();
+        pb.get("/get", response)
+            .use_access_log(::access_log::LogFormat::Combined, ::access_log::LogTarget::File { path: path.clone(), max_bytes: 1024 * 1024, keep: 1 });
+        let p = pb.done();
+        let mut req = Request::new(Method::Get, Uri::from_str("/get?a=b").unwrap());
+        req.headers_mut().set_raw("User-Agent", "pony-test");
+        p.call(req).wait().unwrap();
+        p.call(Request::new(Method::Post, Uri::from_str("/missing").unwrap())).wait().unwrap();
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("- - - ["), "{}", lines[0]);
+        assert!(lines[0].ends_with("\"GET /get?a=b HTTP/1.1\" 200 - \"-\" \"pony-test\""), "{}", lines[0]);
+        assert!(lines[1].contains("\"POST /missing HTTP/1.1\" 404 -"), "{}", lines[1]);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn metrics() {
+        let mut pb = PonyBuilder::new();
+        pb.get("/get", response)
+            .use_static("examples/public")
+            .use_metrics("/metrics");
+        let p = pb.done();
+        for path in &["/get", "/get", "/index.html", "/missing.txt"] {
+            p.call(Request::new(Method::Get, Uri::from_str(path).unwrap())).wait().unwrap();
+        }
+        p.call(Request::new(Method::Delete, Uri::from_str("/nowhere").unwrap())).wait().unwrap();
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/metrics").unwrap())).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let body = String::from_utf8(res.body().concat2().wait().unwrap().to_vec()).unwrap();
+        assert!(body.contains("pony_requests_total{kind=\"route\",route=\"/get\",method=\"GET\",status=\"200\"} 2\n"), "{}", body);
+        assert!(body.contains("pony_requests_total{kind=\"static\",route=\"/\",method=\"GET\",status=\"200\"} 1\n"), "{}", body);
+        assert!(body.contains("pony_requests_total{kind=\"static\",route=\"/\",method=\"GET\",status=\"404\"} 1\n"), "{}", body);
+        assert!(body.contains("pony_requests_total{kind=\"unmatched\",route=\"\",method=\"DELETE\",status=\"404\"} 1\n"), "{}", body);
+        assert!(body.contains("pony_requests_in_flight 1\n"), "{}", body);
+        assert!(pb.metrics().unwrap().render().contains("route=\"/metrics\",method=\"GET\",status=\"200\"} 1"));
     }
-}
\ No newline at end of file
+
+    fn echo_id(req: Request) -> HyperResult {
+        let id = ::request_id::get(&req).unwrap_or("none").to_string();
+        Box::new(ok(Response::new().with_body(id)))
+    }
+
+    #[test]
+    fn request_ids() {
+        let mut pb = PonyBuilder::new();
+        pb.get("/id", echo_id)
+            .use_request_ids(::request_id::RequestIdPolicy::TrustIncoming);
+        let p = pb.done();
+        let mut req = Request::new(Method::Get, Uri::from_str("/id").unwrap());
+        req.headers_mut().set_raw("X-Request-Id", "upstream-1");
+        let res = p.call(req).wait().unwrap();
+        assert_eq!(::request_id::from_headers(res.headers()), Some("upstream-1"));
+        assert!(&*res.body().concat2().wait().unwrap() == b"upstream-1");
+        let mut req = Request::new(Method::Get, Uri::from_str("/missing").unwrap());
+        req.headers_mut().set_raw("Accept", "application/json");
+        let res = p.call(req).wait().unwrap();
+        let id = ::request_id::from_headers(res.headers()).unwrap().to_string();
+        assert_eq!(id.len(), 32);
+        let body = String::from_utf8(res.body().concat2().wait().unwrap().to_vec()).unwrap();
+        assert!(body.contains(&id), "{}", body);
+        pb.use_request_ids(::request_id::RequestIdPolicy::Generate);
+        let mut req = Request::new(Method::Get, Uri::from_str("/id").unwrap());
+        req.headers_mut().set_raw("X-Request-Id", "upstream-1");
+        let res = pb.done().call(req).wait().unwrap();
+        assert_ne!(::request_id::from_headers(res.headers()), Some("upstream-1"));
+        let mut headers = Headers::new();
+        headers.set_raw("X-Request-Id", "upstream-1");
+        assert_eq!(p.request_id(&headers), Some("upstream-1"));
+        assert_eq!(PonyBuilder::new().done().request_id(&headers), None);
+    }
+
+    fn never(_req: Request) -> HyperResult {
+        Box::new(::futures::future::empty())
+    }
+
+    #[test]
+    fn timeouts() {
+        let mut pb = PonyBuilder::new();
+        pb.get("/never", never)
+            .get("/slow", never)
+            .get("/get", response)
+            .use_error_page(StatusCode::GatewayTimeout, "examples/public/files/a.txt")
+            .use_timeout(::std::time::Duration::from_millis(20))
+            .use_route_timeout(Method::Get, "/slow", ::std::time::Duration::from_millis(60))
+            .use_timeout_status(StatusCode::GatewayTimeout);
+        let p = pb.done();
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/never").unwrap())).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::GatewayTimeout);
+        assert!(&*res.body().concat2().wait().unwrap() == b"a\n");
+        let start = ::std::time::Instant::now();
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/slow").unwrap())).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::GatewayTimeout);
+        assert!(start.elapsed() >= ::std::time::Duration::from_millis(40));
+        assert_eq!(status_boiler(&pb, "/get"), StatusCode::Ok);
+    }
+
+    #[test]
+    fn rate_limits() {
+        use super::super::rate_limit::{Quota, RateLimit};
+        let mut pb = PonyBuilder::new();
+        pb.get("/get", response)
+            .use_error_page(StatusCode::TooManyRequests, "examples/public/files/a.txt")
+            .use_rate_limit("/", RateLimit::new(Quota::per_minute(1)));
+        let mut p = pb.done();
+        p.remote_addr = Some("127.0.0.1:5000".parse().unwrap());
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/get").unwrap())).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.headers().get_raw("RateLimit-Remaining").unwrap().one(), Some(&b"0"[..]));
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/get").unwrap())).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        assert_eq!(res.headers().get_raw("Retry-After").unwrap().one(), Some(&b"60"[..]));
+        assert!(&*res.body().concat2().wait().unwrap() == b"a\n");
+        p.remote_addr = Some("127.0.0.2:5000".parse().unwrap());
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/get").unwrap())).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    fn echo(req: Request) -> HyperResult {
+        Box::new(req.body().concat2().map(|body| Response::new().with_body(body)))
+    }
+
+    #[test]
+    fn body_limits() {
+        let mut pb = PonyBuilder::new();
+        pb.post("/echo", echo)
+            .post("/upload", echo)
+            .use_max_body_size(4)
+            .use_route_max_body_size(Method::Post, "/upload", 8);
+        let p = pb.done();
+        let post = |path: &str, body: &'static str, declared: bool| {
+            let mut req = Request::new(Method::Post, Uri::from_str(path).unwrap());
+            if declared {
+                req.headers_mut().set(ContentLength(body.len() as u64));
+            }
+            req.set_body(body);
+            p.call(req).wait().unwrap().status()
+        };
+        assert_eq!(post("/echo", "abcd", true), StatusCode::Ok);
+        assert_eq!(post("/echo", "abcde", true), StatusCode::PayloadTooLarge);
+        assert_eq!(post("/echo", "abcde", false), StatusCode::PayloadTooLarge);
+        assert_eq!(post("/upload", "abcdefgh", false), StatusCode::Ok);
+        assert_eq!(post("/upload", "abcdefghi", false), StatusCode::PayloadTooLarge);
+    }
+
+    fn whoami(req: Request) -> HyperResult {
+        let user = basic_auth::user(&req).unwrap_or("nobody").to_string();
+        Box::new(ok(Response::new().with_body(user)))
+    }
+
+    #[test]
+    fn basic_auth() {
+        let mut pb = PonyBuilder::new();
+        pb.get("/whoami", whoami)
+            .get("/admin/whoami", whoami)
+            .use_static("examples/public/")
+            .use_htpasswd("/files", "files", "examples/users.htpasswd")
+            .use_route_basic_auth(Method::Get, "/admin/whoami", BasicAuth::new("admin").with_cost(4).with_user("root", "toor"));
+        let p = pb.done();
+        let get = |path: &str, auth: Option<&str>| {
+            let mut req = Request::new(Method::Get, Uri::from_str(path).unwrap());
+            req.headers_mut().set_raw(basic_auth::USER_HEADER, "spoofed");
+            if let Some(auth) = auth {
+                req.headers_mut().set_raw("Authorization", format!("Basic {}", ::base64::encode(auth)));
+            }
+            p.call(req).wait().unwrap()
+        };
+        let res = get("/files/a.txt", None);
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert_eq!(res.headers().get_raw("WWW-Authenticate").unwrap().one(), Some(&b"Basic realm=\"files\", charset=\"UTF-8\""[..]));
+        assert_eq!(get("/%66iles/a.txt", Some("bob:wrong")).status(), StatusCode::Unauthorized);
+        assert_eq!(get("/files/a.txt", Some("bob:builder")).status(), StatusCode::Ok);
+        assert_eq!(get("/admin/whoami", Some("bob:builder")).status(), StatusCode::Unauthorized);
+        let body = get("/admin/whoami", Some("root:toor")).body().concat2().wait().unwrap();
+        assert!(&*body == b"root");
+        let body = get("/whoami", None).body().concat2().wait().unwrap();
+        assert!(&*body == b"nobody");
+    }
+
+    fn spoofable(req: Request) -> HyperResult {
+        let user = basic_auth::user(&req).unwrap_or("-");
+        let claims = jwt::claims(&req).map(|c| c.payload().to_string()).unwrap_or_default();
+        let token = csrf::token(&req).unwrap_or_default();
+        let nonce = security_headers::nonce(&req).unwrap_or_default();
+        Box::new(ok(Response::new().with_body(format!("{}{}{}{}", user, claims, token, nonce))))
+    }
+
+    #[test]
+    fn spoofed_headers() {
+        let mut pb = PonyBuilder::new();
+        pb.get("/", spoofable);
+        let p = pb.done();
+        let mut req = Request::new(Method::Get, Uri::from_str("/").unwrap());
+        req.headers_mut().set_raw(basic_auth::USER_HEADER, "root");
+        req.headers_mut().set_raw(jwt::CLAIMS_HEADER, "eyJzdWIiOiJtYWxsb3J5In0");
+        req.headers_mut().set_raw(csrf::ISSUED_HEADER, "spoofed");
+        req.headers_mut().set_raw(security_headers::NONCE_HEADER, "spoofed");
+        let body = p.call(req).wait().unwrap().body().concat2().wait().unwrap();
+        assert!(&*body == b"-");
+    }
+
+    fn subject(req: Request) -> HyperResult {
+        let sub = jwt::claims(&req).and_then(|c| c.subject().map(String::from)).unwrap_or_default();
+        Box::new(ok(Response::new().with_body(sub)))
+    }
+
+    #[test]
+    fn jwt_auth() {
+        let secret = b"a shared secret for the tests!!!";
+        let signed = format!("{}.{}", ::base64::encode_config(r#"{"alg":"HS256"}"#, ::base64::URL_SAFE_NO_PAD),
+                             ::base64::encode_config(r#"{"sub":"carol"}"#, ::base64::URL_SAFE_NO_PAD));
+        let signature = super::super::sha256::hmac(secret, signed.as_bytes());
+        let token = format!("{}.{}", signed, ::base64::encode_config(&signature, ::base64::URL_SAFE_NO_PAD));
+        let mut pb = PonyBuilder::new();
+        pb.get("/subject", subject)
+            .get("/api/subject", subject)
+            .use_jwt_auth("/api", JwtAuth::new("api").with_hmac_secret(secret));
+        let p = pb.done();
+        let get = |path: &str, auth: Option<&str>| {
+            let mut req = Request::new(Method::Get, Uri::from_str(path).unwrap());
+            req.headers_mut().set_raw(jwt::CLAIMS_HEADER, "eyJzdWIiOiJtYWxsb3J5In0");
+            if let Some(auth) = auth {
+                req.headers_mut().set_raw("Authorization", auth.to_string());
+            }
+            p.call(req).wait().unwrap()
+        };
+        let res = get("/api/subject", None);
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert_eq!(res.headers().get_raw("WWW-Authenticate").unwrap().one(), Some(&b"Bearer realm=\"api\""[..]));
+        let res = get("/api/subject", Some(&format!("Bearer {}x", token)));
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert!(res.headers().get_raw("WWW-Authenticate").unwrap().one().unwrap().ends_with(b"error_description=\"The token's signature is invalid\""));
+        let body = get("/api/subject", Some(&format!("Bearer {}", token))).body().concat2().wait().unwrap();
+        assert!(&*body == b"carol");
+        let body = get("/subject", None).body().concat2().wait().unwrap();
+        assert!(&*body == b"");
+    }
+
+    fn form_token(req: Request) -> HyperResult {
+        let token = csrf::token(&req).unwrap_or_default().to_string();
+        Box::new(ok(Response::new().with_body(token)))
+    }
+
+    #[test]
+    fn csrf() {
+        let mut pb = PonyBuilder::new();
+        pb.get("/form", form_token)
+            .post("/form", echo)
+            .post("/api/items", echo)
+            .use_csrf(Csrf::new().with_exempt_prefix("/api"));
+        let p = pb.done();
+        let res = p.call(Request::new(Method::Get, Uri::from_str("/form").unwrap())).wait().unwrap();
+        let cookie = res.headers().get::<::hyper::header::SetCookie>().unwrap().0[0].clone();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        let token = String::from_utf8(res.body().concat2().wait().unwrap().to_vec()).unwrap();
+        assert_eq!(cookie, format!("csrf_token={}", token));
+        let post = |path: &str, cookie: Option<&str>, body: String| {
+            let mut req = Request::new(Method::Post, Uri::from_str(path).unwrap());
+            req.headers_mut().set(ContentType::form_url_encoded());
+            req.headers_mut().set_raw(csrf::ISSUED_HEADER, "spoofed");
+            if let Some(cookie) = cookie {
+                req.headers_mut().set_raw("Cookie", cookie.to_string());
+            }
+            req.set_body(body);
+            p.call(req).wait().unwrap()
+        };
+        let res = post("/form", Some(&cookie), format!("csrf_token={}", token));
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert!(res.headers().get::<::hyper::header::SetCookie>().is_none());
+        assert_eq!(&*res.body().concat2().wait().unwrap(), format!("csrf_token={}", token).as_bytes());
+        assert_eq!(post("/form", None, format!("csrf_token={}", token)).status(), StatusCode::Forbidden);
+        assert_eq!(post("/form", Some(&cookie), String::from("csrf_token=spoofed")).status(), StatusCode::Forbidden);
+        assert_eq!(post("/api/items", None, String::new()).status(), StatusCode::Ok);
+    }
+
+    fn nonce(req: Request) -> HyperResult {
+        let nonce = security_headers::nonce(&req).unwrap_or_default().to_string();
+        Box::new(ok(Response::new().with_body(nonce)))
+    }
+
+    #[test]
+    fn security_headers() {
+        let mut pb = PonyBuilder::new();
+        pb.get("/nonce", nonce)
+            .get("/embed", nonce)
+            .use_static("examples/public/")
+            .use_security_headers(SecurityHeaders::new())
+            .use_route_security_headers(Method::Get, "/embed", SecurityHeaders::new().without("X-Frame-Options"));
+        let p = pb.done();
+        let get = |path: &str| {
+            let mut req = Request::new(Method::Get, Uri::from_str(path).unwrap());
+            req.headers_mut().set_raw(security_headers::NONCE_HEADER, "spoofed");
+            p.call(req).wait().unwrap()
+        };
+        let header = |res: &Response, name: &str| res.headers().get_raw(name).and_then(|raw| raw.one()).map(|v| String::from_utf8(v.to_vec()).unwrap());
+        let res = get("/nonce");
+        let csp = header(&res, "Content-Security-Policy").unwrap();
+        let body = String::from_utf8(res.body().concat2().wait().unwrap().to_vec()).unwrap();
+        assert!(body != "spoofed");
+        assert!(csp.contains(&format!("'nonce-{}'", body)));
+        assert_eq!(get("/files/a.txt").status(), StatusCode::Ok);
+        for res in [get("/files/a.txt"), get("/missing")] {
+            assert_eq!(header(&res, "X-Content-Type-Options"), Some(String::from("nosniff")));
+            assert_eq!(header(&res, "X-Frame-Options"), Some(String::from("DENY")));
+        }
+        assert_eq!(get("/missing").status(), StatusCode::NotFound);
+        let res = get("/embed");
+        assert_eq!(header(&res, "X-Frame-Options"), None);
+        assert!(header(&res, "Referrer-Policy").is_some());
+    }
+
+    fn ws(_req: Request, _ws: ::websocket::WebSocket<::tokio_core::net::TcpStream>) -> Box<dyn Future<Item = (), Error = ()>> {
+        Box::new(ok(()))
+    }
+
+    #[test]
+    fn websocket_upgrades() {
+        let mut pb = PonyBuilder::new();
+        pb.websocket_from("/ws", &["https://example.com"], ws);
+        let mut p = pb.done();
+        let handshake = |origin: Option<&str>| {
+            let mut req = Request::new(Method::Get, Uri::from_str("/ws").unwrap());
+            req.headers_mut().set_raw("Upgrade", "websocket");
+            req.headers_mut().set_raw("Connection", "Upgrade");
+            req.headers_mut().set_raw("Sec-WebSocket-Version", "13");
+            req.headers_mut().set_raw("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==");
+            if let Some(origin) = origin {
+                req.headers_mut().set_raw("Origin", origin);
+            }
+            req
+        };
+        let res = p.call(handshake(Some("https://example.com"))).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::NotImplemented);
+        assert!(p.upgrade.lock().unwrap().is_none());
+        p.upgrades = true;
+        assert_eq!(p.call(handshake(None)).wait().unwrap().status(), StatusCode::Forbidden);
+        assert_eq!(p.call(handshake(Some("https://evil.example"))).wait().unwrap().status(), StatusCode::Forbidden);
+        assert!(p.upgrade.lock().unwrap().is_none());
+        let res = p.call(handshake(Some("https://example.com"))).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::SwitchingProtocols);
+        assert!(p.upgrade.lock().unwrap().is_some());
+    }
+}
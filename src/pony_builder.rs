@@ -1,43 +1,110 @@
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
 use std::io;
-use std::path::{PathBuf};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use hyper::server::NewService;
-use hyper::{Request, Response, Error};
+use hyper::{Request, Response, Error, Method, StatusCode};
 
-use super::pony::{Pony, ETag};
+use super::pony::{Pony, ETag, SymlinkPolicy};
+use super::static_mount::StaticMount;
+use super::embed::EmbeddedBundle;
+use super::source::{DiskSource, StaticSource};
+use super::archive::ArchiveSource;
+use super::file_cache::{CacheStats, FileCache};
+use super::error_page::{ErrorPage, ErrorPages};
+use super::error::{ConfigProblem, PonyError};
+use super::problem::{ErrorHook, FallibleResult, Handler, PanicHook, ResponseError};
+use super::access_log::{AccessLog, LogFormat, LogTarget};
+use super::metrics::Metrics;
+use super::request_id::RequestIdPolicy;
+use super::timeout::Timeouts;
+use super::rate_limit::{RateLimit, RateLimits};
+use super::scope::{Scope, Scoped};
+use super::basic_auth::BasicAuth;
+use super::jwt::JwtAuth;
+use super::csrf::Csrf;
+use super::security_headers::SecurityHeaders;
+use super::websocket::WebSocketHandler;
+use super::server::{self, ConnectionLimits};
 use super::Callback;
 use std::iter::FromIterator;
 
 ///Builder struct for main hyper service
 pub struct PonyBuilder {
-    gets: HashMap<String, Callback>,
-    posts: HashMap<String, Callback>,
-    puts: HashMap<String, Callback>,
-    deletes: HashMap<String, Callback>,
+    gets: HashMap<String, Handler>,
+    posts: HashMap<String, Handler>,
+    puts: HashMap<String, Handler>,
+    deletes: HashMap<String, Handler>,
+    websockets: HashMap<String, WebSocketHandler>,
+    websocket_origins: HashMap<String, Vec<String>>,
     static_path: String,
+    static_layers: Vec<Arc<dyn StaticSource>>,
     static_enabled: bool,
     static_logging_enabled: bool,
     static_gzip_enabled: bool,
+    error_pages: ErrorPages,
     not_found_path: String,
-    custom_not_found: bool,
+    problems: Vec<ConfigProblem>,
+    error_hook: Option<ErrorHook>,
+    panic_hook: Option<PanicHook>,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Option<Arc<Metrics>>,
+    metrics_path: String,
+    request_ids: Option<RequestIdPolicy>,
+    timeouts: Option<Timeouts>,
+    rate_limits: RateLimits,
+    body_limits: Scoped<u64>,
+    basic_auth: Scoped<BasicAuth>,
+    jwt_auth: Scoped<JwtAuth>,
+    csrf: Option<Csrf>,
+    security_headers: Scoped<SecurityHeaders>,
+    connection_limits: ConnectionLimits,
     known_extensions: HashSet<String>,
     etag: ETag,
+    autoindex_enabled: bool,
+    autoindex_hide_dotfiles: bool,
+    dotfiles_enabled: bool,
+    symlinks: SymlinkPolicy,
+    spa_fallback_enabled: bool,
+    mounts: Vec<StaticMount>,
+    file_cache: Option<Arc<Mutex<FileCache>>>,
 }
 
 impl PonyBuilder {
     ///Create a new (default) builder
+    #[allow(clippy::useless_conversion)]
     pub fn new() -> PonyBuilder {
         Self {
             gets: HashMap::new(),
             posts: HashMap::new(),
             puts: HashMap::new(),
             deletes: HashMap::new(),
+            websockets: HashMap::new(),
+            websocket_origins: HashMap::new(),
             static_path: String::new(),
+            static_layers: vec!(),
             static_enabled: false,
             static_logging_enabled: false,
             static_gzip_enabled: false,
+            error_pages: ErrorPages::new(),
             not_found_path: String::new(),
-            custom_not_found: false,
+            problems: vec!(),
+            error_hook: None,
+            panic_hook: None,
+            access_log: None,
+            metrics: None,
+            metrics_path: String::new(),
+            request_ids: None,
+            timeouts: None,
+            rate_limits: RateLimits::new(),
+            body_limits: Scoped::new(),
+            basic_auth: Scoped::new(),
+            jwt_auth: Scoped::new(),
+            csrf: None,
+            security_headers: Scoped::new(),
+            connection_limits: ConnectionLimits::default(),
             known_extensions: HashSet::from_iter(
                                         vec![
                                             String::from("html"),
@@ -56,44 +123,213 @@ impl PonyBuilder {
                                             String::from("gif"),
                                             String::from("map"),
                                         ].into_iter()),
-            etag: ETag::default()
+            etag: ETag::default(),
+            autoindex_enabled: false,
+            autoindex_hide_dotfiles: false,
+            dotfiles_enabled: false,
+            symlinks: SymlinkPolicy::default(),
+            spa_fallback_enabled: false,
+            mounts: vec!(),
+            file_cache: None,
         }
     }
 }
 
+impl Default for PonyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PonyBuilder {
     ///Add a new get request
     pub fn get(&mut self, path: &str, cb: Callback) -> &mut Self {
-        self.gets.insert(path.to_string(), cb);
+        Self::add_route(&mut self.gets, &mut self.problems, Method::Get, path, Handler::Callback(cb));
         self
     }
     ///Add a new post request
     pub fn post(&mut self, path: &str, cb: Callback) -> &mut Self {
-        self.posts.insert(path.to_string(), cb);
+        Self::add_route(&mut self.posts, &mut self.problems, Method::Post, path, Handler::Callback(cb));
         self
     }
     ///Add a new put request
     pub fn put(&mut self, path: &str, cb: Callback) -> &mut Self {
-        self.puts.insert(path.to_string(), cb);
+        Self::add_route(&mut self.puts, &mut self.problems, Method::Put, path, Handler::Callback(cb));
         self
     }
     ///Add a new delete request
     pub fn delete(&mut self, path: &str, cb: Callback) -> &mut Self {
-        self.deletes.insert(path.to_string(), cb);
+        Self::add_route(&mut self.deletes, &mut self.problems, Method::Delete, path, Handler::Callback(cb));
+        self
+    }
+    ///Add a new get request whose handler can fail with
+    ///any `ResponseError`
+    pub fn get_fallible<E: ResponseError + 'static>(&mut self, path: &str, cb: fn(Request) -> FallibleResult<E>) -> &mut Self {
+        Self::add_route(&mut self.gets, &mut self.problems, Method::Get, path, Handler::fallible(cb));
+        self
+    }
+    ///Add a new post request whose handler can fail with
+    ///any `ResponseError`
+    pub fn post_fallible<E: ResponseError + 'static>(&mut self, path: &str, cb: fn(Request) -> FallibleResult<E>) -> &mut Self {
+        Self::add_route(&mut self.posts, &mut self.problems, Method::Post, path, Handler::fallible(cb));
+        self
+    }
+    ///Add a new put request whose handler can fail with
+    ///any `ResponseError`
+    pub fn put_fallible<E: ResponseError + 'static>(&mut self, path: &str, cb: fn(Request) -> FallibleResult<E>) -> &mut Self {
+        Self::add_route(&mut self.puts, &mut self.problems, Method::Put, path, Handler::fallible(cb));
         self
     }
+    ///Add a new delete request whose handler can fail with
+    ///any `ResponseError`
+    pub fn delete_fallible<E: ResponseError + 'static>(&mut self, path: &str, cb: fn(Request) -> FallibleResult<E>) -> &mut Self {
+        Self::add_route(&mut self.deletes, &mut self.problems, Method::Delete, path, Handler::fallible(cb));
+        self
+    }
+    ///Accept WebSocket connections on a path, the handler is
+    ///given the upgraded request and a stream and sink of
+    ///messages. Connections are only upgraded by `serve`
+    pub fn websocket(&mut self, path: &str, handler: WebSocketHandler) -> &mut Self {
+        if self.websockets.insert(path.to_string(), handler).is_some() {
+            self.problems.push(ConfigProblem::ConflictingRoute { method: Method::Get, path: path.to_string() });
+        }
+        self
+    }
+    ///Accept WebSocket connections on a path only from pages on
+    ///one of `origins`, such as `https://example.com`, so other
+    ///sites can't open them with a visitor's cookies
+    pub fn websocket_from(&mut self, path: &str, origins: &[&str], handler: WebSocketHandler) -> &mut Self {
+        self.websocket_origins.insert(path.to_string(), origins.iter().map(|o| o.to_string()).collect());
+        self.websocket(path, handler)
+    }
+    ///Called whenever a fallible handler returns an error,
+    ///returning `Some` from the hook replaces the error's response
+    pub fn use_error_hook(&mut self, hook: ErrorHook) -> &mut Self {
+        self.error_hook = Some(hook);
+        self
+    }
+    ///Called with the method, path and message whenever a handler
+    ///panics, the client is sent a 500. Without a hook these are
+    ///logged as errors with the `log` crate
+    pub fn use_panic_hook(&mut self, hook: PanicHook) -> &mut Self {
+        self.panic_hook = Some(hook);
+        self
+    }
+    ///Insert a route, the last handler added for a path is used
+    ///but any earlier one is reported as a conflict by `build`
+    fn add_route(routes: &mut HashMap<String, Handler>, problems: &mut Vec<ConfigProblem>, method: Method, path: &str, handler: Handler) {
+        if routes.insert(path.to_string(), handler).is_some() {
+            problems.push(ConfigProblem::ConflictingRoute { method, path: path.to_string() });
+        }
+    }
     ///Serve static files
     ///path is the base path to search
     pub fn use_static(&mut self, path: &str) -> &mut PonyBuilder {
-        let as_buf = PathBuf::from(&path);
-        if !as_buf.exists() {
-            panic!(format!("Static path does not exist\n{:?}", &path));
-        }
-        if as_buf.is_file() {
-            panic!(format!("Static path must be a directory\n{:?}", &path));
-        }
+        let result = self.static_dir(path);
+        self.expect(result)
+    }
+    ///Like `use_static` but a bad path is reported by `build`
+    ///instead of panicking
+    pub fn try_use_static(&mut self, path: &str) -> &mut Self {
+        let result = self.static_dir(path);
+        self.record(result)
+    }
+    fn static_dir(&mut self, path: &str) -> Result<(), ConfigProblem> {
+        Self::check_source(&DiskSource::new(path))?;
         self.static_path = path.to_string();
         self.static_enabled = true;
+        Ok(())
+    }
+    ///Serve static files from an additional directory
+    ///under the mount's url prefix, the mount with the longest
+    ///matching prefix will be used
+    pub fn use_static_mount(&mut self, mount: StaticMount) -> &mut Self {
+        let result = self.static_mount(mount);
+        self.expect(result)
+    }
+    ///Like `use_static_mount` but a bad layer is reported by `build`
+    ///instead of panicking
+    pub fn try_use_static_mount(&mut self, mount: StaticMount) -> &mut Self {
+        let result = self.static_mount(mount);
+        self.record(result)
+    }
+    fn static_mount(&mut self, mount: StaticMount) -> Result<(), ConfigProblem> {
+        for layer in &mount.layers {
+            Self::check_source(&**layer)?;
+        }
+        self.mounts.push(mount);
+        Ok(())
+    }
+    ///Add a directory to search, in order, after the
+    ///static path when a file is not found there
+    pub fn add_static_layer(&mut self, path: &str) -> &mut Self {
+        self.use_static_source(Arc::new(DiskSource::new(path)))
+    }
+    ///Like `add_static_layer` but a bad path is reported by `build`
+    ///instead of panicking
+    pub fn try_add_static_layer(&mut self, path: &str) -> &mut Self {
+        self.try_use_static_source(Arc::new(DiskSource::new(path)))
+    }
+    ///Serve static files compiled into the binary with
+    ///`embed::generate`, these are searched after `use_static`
+    ///and any layers added before this
+    pub fn use_embedded(&mut self, bundle: &'static EmbeddedBundle) -> &mut Self {
+        self.use_static_source(Arc::new(bundle))
+    }
+    ///Serve static files from any `StaticSource`, these are
+    ///searched after `use_static` and any layers added before this
+    pub fn use_static_source(&mut self, source: Arc<dyn StaticSource>) -> &mut Self {
+        let result = self.static_source(source);
+        self.expect(result)
+    }
+    ///Like `use_static_source` but a source that fails to verify
+    ///is reported by `build` instead of panicking
+    pub fn try_use_static_source(&mut self, source: Arc<dyn StaticSource>) -> &mut Self {
+        let result = self.static_source(source);
+        self.record(result)
+    }
+    fn static_source(&mut self, source: Arc<dyn StaticSource>) -> Result<(), ConfigProblem> {
+        Self::check_source(&*source)?;
+        self.static_layers.push(source);
+        Ok(())
+    }
+    ///Serve static files out of a `.zip` or `.tar` archive without
+    ///extracting it, these are searched after `use_static` and any
+    ///layers added before this. The archive is indexed immediately
+    pub fn use_archive(&mut self, path: &str) -> &mut Self {
+        let result = self.archive(path);
+        self.expect(result)
+    }
+    ///Like `use_archive` but an archive that can't be read is
+    ///reported by `build` instead of panicking
+    pub fn try_use_archive(&mut self, path: &str) -> &mut Self {
+        let result = self.archive(path);
+        self.record(result)
+    }
+    fn archive(&mut self, path: &str) -> Result<(), ConfigProblem> {
+        let source = ArchiveSource::open(path).map_err(|e| ConfigProblem::Archive {
+            path: path.to_string(),
+            message: e.to_string(),
+        })?;
+        self.static_source(Arc::new(source))
+    }
+    fn check_source(source: &dyn StaticSource) -> Result<(), ConfigProblem> {
+        source.verify().map_err(ConfigProblem::Source)
+    }
+    ///Panic with a problem found by one of the
+    ///non-`try_` methods
+    fn expect(&mut self, result: Result<(), ConfigProblem>) -> &mut Self {
+        if let Err(problem) = result {
+            panic!("{}", problem);
+        }
+        self
+    }
+    ///Keep a problem found by one of the `try_`
+    ///methods to be returned by `build`
+    fn record(&mut self, result: Result<(), ConfigProblem>) -> &mut Self {
+        if let Err(problem) = result {
+            self.problems.push(problem);
+        }
         self
     }
     ///turns on logging for attempts to find static files
@@ -111,31 +347,55 @@ impl PonyBuilder {
     ///provide a custom not found html page
     /// path is the relative path to said file
     pub fn use_not_found(&mut self, path: &str) -> &mut Self {
-        let as_buf = PathBuf::from(&path);
-        if !as_buf.exists() ||
-        !as_buf.is_file() {
-            panic!(format!("Not found path does not exist\n{:?}", &path));
-        }
-        if as_buf.is_dir() {
-            panic!(format!("Not found path is a directory"))
+        self.use_error_page(StatusCode::NotFound, path)
+    }
+    ///Like `use_not_found` but a file that can't be read is
+    /// reported by `build` instead of panicking
+    pub fn try_use_not_found(&mut self, path: &str) -> &mut Self {
+        self.try_use_error_page(StatusCode::NotFound, path)
+    }
+    ///provide a page to send with an error status, the file
+    /// is read immediately and sent with its status and a
+    /// Content-Type based on its extension. Clients that
+    /// accept json will get a json body instead
+    pub fn use_error_page(&mut self, status: StatusCode, path: &str) -> &mut Self {
+        let result = self.error_page(status, path);
+        self.expect(result)
+    }
+    ///Like `use_error_page` but a file that can't be read is
+    /// reported by `build` instead of panicking
+    pub fn try_use_error_page(&mut self, status: StatusCode, path: &str) -> &mut Self {
+        let result = self.error_page(status, path);
+        self.record(result)
+    }
+    fn error_page(&mut self, status: StatusCode, path: &str) -> Result<(), ConfigProblem> {
+        let page = ErrorPage::load(path).map_err(|e| ConfigProblem::ErrorPage {
+            status,
+            path: path.to_string(),
+            message: e.to_string(),
+        })?;
+        if status == StatusCode::NotFound {
+            self.not_found_path = path.to_string();
         }
-        self.not_found_path = path.to_string();
-        self.custom_not_found = true;
-        self
+        self.error_pages.insert(status, page);
+        Ok(())
     }
     ///Override the default known extension
     /// Useful if you want to limit searching
     /// to a specific set of file types
+    #[allow(clippy::into_iter_on_ref)]
     pub fn set_know_extensions(&mut self, list: &[&str]) -> &mut Self {
         self.known_extensions = HashSet::from_iter(list.into_iter().map(|e| e.to_string()));
         self
     }
     ///Add a new ext to the known extension list
+    #[allow(clippy::into_iter_on_ref)]
     pub fn add_known_extension(&mut self, exts: &[&str]) -> &mut Self {
         self.known_extensions.extend(exts.into_iter().map(|e| e.to_string()));
         self
     }
     ///Remove an ext from the known extension list
+    #[allow(clippy::into_iter_on_ref)]
     pub fn remove_known_extension(&mut self, exts: &[&str]) -> &mut Self {
         for ext in exts.into_iter() {
             self.known_extensions.remove(*ext);
@@ -149,19 +409,342 @@ impl PonyBuilder {
         self
     }
 
+    ///render a listing for static directories that
+    ///do not contain an index.html, this will be json if the
+    ///client accepts `application/json` otherwise html
+    pub fn use_autoindex(&mut self, hide_dotfiles: bool) -> &mut Self {
+        self.autoindex_enabled = true;
+        self.autoindex_hide_dotfiles = hide_dotfiles;
+        self
+    }
+
+    ///serve files and directories whose name begins with a `.`
+    ///by default these will return a 404
+    pub fn use_dotfiles(&mut self) -> &mut Self {
+        self.dotfiles_enabled = true;
+        self
+    }
+    ///sets the option for following symbolic links under the static path
+    ///defaults to `SymlinkPolicy::WithinRoot`
+    pub fn use_symlink_policy(&mut self, policy: SymlinkPolicy) -> &mut Self {
+        self.symlinks = policy;
+        self
+    }
+    ///serve the root index.html for any static request
+    ///that is not found and has no file extension, this
+    ///allows client side routing in single page applications
+    pub fn use_spa_fallback(&mut self) -> &mut Self {
+        self.spa_fallback_enabled = true;
+        self
+    }
+
+    ///write a line for every request, static or routed, in
+    ///the given format. The log is shared by every `Pony`
+    ///this builder creates
+    pub fn use_access_log(&mut self, format: LogFormat, target: LogTarget) -> &mut Self {
+        let result = self.access_log(format, target);
+        self.expect(result)
+    }
+    ///Like `use_access_log` but a file that can't be opened
+    ///is reported by `build` instead of panicking
+    pub fn try_use_access_log(&mut self, format: LogFormat, target: LogTarget) -> &mut Self {
+        let result = self.access_log(format, target);
+        self.record(result)
+    }
+    fn access_log(&mut self, format: LogFormat, target: LogTarget) -> Result<(), ConfigProblem> {
+        let log = AccessLog::new(format, target.clone()).map_err(|e| ConfigProblem::AccessLog {
+            target: format!("{:?}", target),
+            message: e.to_string(),
+        })?;
+        self.access_log = Some(Arc::new(log));
+        Ok(())
+    }
+
+    ///collect request counts, status counts, in flight requests
+    ///and latency histograms, served in the Prometheus text
+    ///format on a GET to `path`. Static files are grouped by the
+    ///prefix of the mount that served them
+    pub fn use_metrics(&mut self, path: &str) -> &mut Self {
+        self.metrics = Some(Arc::new(Metrics::new()));
+        self.metrics_path = path.to_string();
+        self
+    }
+    ///The metrics collected by every `Pony` this builder
+    ///creates if `use_metrics` was called
+    pub fn metrics(&self) -> Option<Arc<Metrics>> {
+        self.metrics.clone()
+    }
+
+    ///give every request an id, stored in its `X-Request-Id`
+    ///header where handlers can read it with `request_id::get`.
+    ///The id is added to access log lines, error pages and
+    ///echoed back in the response's `X-Request-Id` header
+    pub fn use_request_ids(&mut self, policy: RequestIdPolicy) -> &mut Self {
+        self.request_ids = Some(policy);
+        self.error_pages.use_request_ids();
+        self
+    }
+
+    ///drop any route handler that has not responded within `limit`
+    ///and send the timeout status instead, using its error page
+    pub fn use_timeout(&mut self, limit: Duration) -> &mut Self {
+        self.timeouts.get_or_insert_with(Timeouts::new).limits.add(Scope::Prefix(String::from("/")), limit);
+        self
+    }
+    ///a time limit for a single route, this overrides
+    ///the limit set with `use_timeout`
+    pub fn use_route_timeout(&mut self, method: Method, path: &str, limit: Duration) -> &mut Self {
+        self.timeouts.get_or_insert_with(Timeouts::new).limits.add(Scope::Route(method, path.to_string()), limit);
+        self
+    }
+    ///the status sent when a handler times out,
+    ///defaults to 503 Service Unavailable
+    pub fn use_timeout_status(&mut self, status: StatusCode) -> &mut Self {
+        self.timeouts.get_or_insert_with(Timeouts::new).status = status;
+        self
+    }
+
+    ///limit requests to every path under `prefix`, clients over
+    ///the limit get a 429 with a `Retry-After` header. Responses
+    ///under a limit include `RateLimit-*` headers
+    pub fn use_rate_limit(&mut self, prefix: &str, limit: RateLimit) -> &mut Self {
+        self.rate_limits.add(Scope::Prefix(prefix.to_string()), limit);
+        self
+    }
+    ///limit requests to a single route, this is used
+    ///in place of any prefix limit covering the route
+    pub fn use_route_rate_limit(&mut self, method: Method, path: &str, limit: RateLimit) -> &mut Self {
+        self.rate_limits.add(Scope::Route(method, path.to_string()), limit);
+        self
+    }
+
+    ///the largest request body any route handler will be given,
+    ///larger bodies get a 413 and the handler is dropped
+    pub fn use_max_body_size(&mut self, max_bytes: u64) -> &mut Self {
+        self.body_limits.add(Scope::Prefix(String::from("/")), max_bytes);
+        self
+    }
+    ///the largest request body for a single route, this
+    ///overrides the limit set with `use_max_body_size`
+    pub fn use_route_max_body_size(&mut self, method: Method, path: &str, max_bytes: u64) -> &mut Self {
+        self.body_limits.add(Scope::Route(method, path.to_string()), max_bytes);
+        self
+    }
+
+    ///require a Basic auth login for every path under `prefix`,
+    ///including static files. Handlers can read the user's name
+    ///with `basic_auth::user`
+    pub fn use_basic_auth(&mut self, prefix: &str, auth: BasicAuth) -> &mut Self {
+        self.basic_auth.add(Scope::Prefix(prefix.to_string()), auth);
+        self
+    }
+    ///require a Basic auth login for a single route, this is
+    ///used in place of any prefix login covering the route
+    pub fn use_route_basic_auth(&mut self, method: Method, path: &str, auth: BasicAuth) -> &mut Self {
+        self.basic_auth.add(Scope::Route(method, path.to_string()), auth);
+        self
+    }
+    ///require a login from the users in an `.htpasswd` file
+    ///for every path under `prefix`.
+    ///
+    ///panics if the file can't be read
+    pub fn use_htpasswd(&mut self, prefix: &str, realm: &str, path: &str) -> &mut Self {
+        let result = self.htpasswd(prefix, realm, path);
+        self.expect(result)
+    }
+    ///require a login from the users in an `.htpasswd` file,
+    ///a file that can't be read is returned by `build`
+    pub fn try_use_htpasswd(&mut self, prefix: &str, realm: &str, path: &str) -> &mut Self {
+        let result = self.htpasswd(prefix, realm, path);
+        self.record(result)
+    }
+    fn htpasswd(&mut self, prefix: &str, realm: &str, path: &str) -> Result<(), ConfigProblem> {
+        let auth = BasicAuth::new(realm).with_htpasswd(path).map_err(|e| ConfigProblem::Htpasswd {
+            path: path.to_string(),
+            message: e.to_string(),
+        })?;
+        self.use_basic_auth(prefix, auth);
+        Ok(())
+    }
+    ///require a bearer token for every path under `prefix`,
+    ///including static files. Handlers can read the token's
+    ///claims with `jwt::claims`
+    pub fn use_jwt_auth(&mut self, prefix: &str, auth: JwtAuth) -> &mut Self {
+        self.jwt_auth.add(Scope::Prefix(prefix.to_string()), auth);
+        self
+    }
+    ///require a bearer token for a single route, this is
+    ///used in place of any prefix requirement covering the route
+    pub fn use_route_jwt_auth(&mut self, method: Method, path: &str, auth: JwtAuth) -> &mut Self {
+        self.jwt_auth.add(Scope::Route(method, path.to_string()), auth);
+        self
+    }
+    ///require POST, PUT and DELETE requests to send back the
+    ///CSRF token issued to the page they came from. Handlers
+    ///can read the token to render with `csrf::token`
+    pub fn use_csrf(&mut self, csrf: Csrf) -> &mut Self {
+        self.csrf = Some(csrf);
+        self
+    }
+    ///add security headers to every response, including
+    ///static files and error pages
+    pub fn use_security_headers(&mut self, headers: SecurityHeaders) -> &mut Self {
+        self.use_prefix_security_headers("/", headers)
+    }
+    ///add security headers to responses for every path under
+    ///`prefix`, in place of any set for a shorter prefix
+    pub fn use_prefix_security_headers(&mut self, prefix: &str, headers: SecurityHeaders) -> &mut Self {
+        self.security_headers.add(Scope::Prefix(prefix.to_string()), headers);
+        self
+    }
+    ///add security headers to a single route's responses,
+    ///in place of any set for a prefix covering the route
+    pub fn use_route_security_headers(&mut self, method: Method, path: &str, headers: SecurityHeaders) -> &mut Self {
+        self.security_headers.add(Scope::Route(method, path.to_string()), headers);
+        self
+    }
+
+    ///replace every connection limit used by `serve`
+    pub fn use_connection_limits(&mut self, limits: ConnectionLimits) -> &mut Self {
+        self.connection_limits = limits;
+        self
+    }
+    ///how long a client may take to send a request's line
+    ///and headers, defaults to 10 seconds
+    pub fn use_header_timeout(&mut self, limit: Duration) -> &mut Self {
+        self.connection_limits.header_timeout = Some(limit);
+        self
+    }
+    ///how long a client may take to send a request's body
+    ///once its headers are in, defaults to 30 seconds
+    pub fn use_body_timeout(&mut self, limit: Duration) -> &mut Self {
+        self.connection_limits.body_timeout = Some(limit);
+        self
+    }
+    ///how long a kept alive connection may wait for its
+    ///next request, defaults to 60 seconds
+    pub fn use_keep_alive_timeout(&mut self, limit: Duration) -> &mut Self {
+        self.connection_limits.keep_alive_timeout = Some(limit);
+        self
+    }
+    ///the largest request line and headers accepted,
+    ///defaults to 32KiB
+    pub fn use_max_header_size(&mut self, max_bytes: usize) -> &mut Self {
+        self.connection_limits.max_header_size = max_bytes;
+        self
+    }
+    ///listen on `addr` and serve requests until the process exits,
+    ///each connection is held to this builder's connection limits
+    pub fn serve(&self, addr: &SocketAddr) -> io::Result<()> {
+        server::serve(self, addr, self.connection_limits)
+    }
+
+    ///keep up to `max_bytes` of static files in memory, files
+    ///larger than `max_file_bytes` will always be read from disk.
+    ///Cached files are re-read when their modified time or length
+    ///changes. The cache is shared by every `Pony` this builder creates
+    pub fn use_file_cache(&mut self, max_bytes: usize, max_file_bytes: usize) -> &mut Self {
+        self.file_cache = Some(Arc::new(Mutex::new(FileCache::new(max_bytes, max_file_bytes))));
+        self
+    }
+    ///The current counters for the file cache
+    ///if one is enabled
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.file_cache.as_ref()
+            .and_then(|cache| cache.lock().ok().map(|cache| cache.stats()))
+    }
+    ///The mount for `use_static` followed by any additional
+    /// mounts, sorted longest prefix first
+    fn static_mounts(&self) -> Vec<StaticMount> {
+        let mut mounts = self.mounts.clone();
+        if self.static_enabled || !self.static_layers.is_empty() {
+            let mut layers: Vec<Arc<dyn StaticSource>> = vec!();
+            if self.static_enabled {
+                layers.push(Arc::new(DiskSource::new(&self.static_path)));
+            }
+            layers.extend(self.static_layers.iter().cloned());
+            let mut root = StaticMount::new("/", "")
+                .use_etag(self.etag)
+                .use_symlink_policy(self.symlinks);
+            root.layers = layers;
+            if self.static_gzip_enabled {
+                root = root.use_gzip();
+            }
+            if self.autoindex_enabled {
+                root = root.use_autoindex(self.autoindex_hide_dotfiles);
+            }
+            if self.dotfiles_enabled {
+                root = root.use_dotfiles();
+            }
+            if self.spa_fallback_enabled {
+                root = root.use_spa_fallback();
+            }
+            mounts.push(root);
+        }
+        mounts.sort_by_key(|m| Reverse(m.prefix.len()));
+        mounts
+    }
+
+    ///Create a `Pony` if no problems were found while configuring
+    /// this builder, otherwise every problem found
+    pub fn build(&self) -> Result<Pony, PonyError> {
+        let mut problems = self.problems.clone();
+        if self.metrics.is_some() && self.gets.contains_key(&self.metrics_path) {
+            problems.push(ConfigProblem::ConflictingRoute { method: Method::Get, path: self.metrics_path.clone() });
+        }
+        for path in self.websockets.keys().filter(|path| self.gets.contains_key(*path)) {
+            problems.push(ConfigProblem::ConflictingRoute { method: Method::Get, path: path.clone() });
+        }
+        let mounts = self.static_mounts();
+        for (idx, mount) in mounts.iter().enumerate() {
+            if mounts[..idx].iter().any(|m| m.prefix == mount.prefix) {
+                problems.push(ConfigProblem::ConflictingMount(mount.prefix.clone()));
+            }
+        }
+        if problems.is_empty() {
+            Ok(self.done())
+        } else {
+            Err(PonyError { problems })
+        }
+    }
+
+    ///Create a `Pony`, ignoring any problems
+    /// found while configuring this builder
+    #[allow(deprecated)]
     pub fn done(&self) -> Pony {
         Pony {
             gets: self.gets.clone(),
             posts: self.posts.clone(),
             puts: self.puts.clone(),
             deletes: self.deletes.clone(),
-            static_path: self.static_path.clone(),
-            static_enabled: self.static_enabled == true,
+            websockets: self.websockets.clone(),
+            websocket_origins: self.websocket_origins.clone(),
+            mounts: self.static_mounts(),
             static_logging: self.static_logging_enabled,
-            not_found_path: self.not_found_path.clone(),
-            custom_not_found: self.custom_not_found == true,
+            error_pages: Arc::new(self.error_pages.clone()),
+            error_hook: self.error_hook,
+            panic_hook: self.panic_hook,
+            access_log: self.access_log.clone(),
+            metrics: self.metrics.clone(),
+            metrics_path: self.metrics_path.clone(),
+            request_ids: self.request_ids,
+            timeouts: self.timeouts.clone(),
+            rate_limits: Arc::new(self.rate_limits.clone()),
+            body_limits: Arc::new(self.body_limits.clone()),
+            basic_auth: Arc::new(self.basic_auth.clone()),
+            jwt_auth: Arc::new(self.jwt_auth.clone()),
+            csrf: self.csrf.clone().map(Arc::new),
+            security_headers: Arc::new(self.security_headers.clone()),
+            remote_addr: None,
+            upgrades: false,
+            upgrade: Arc::new(Mutex::new(None)),
             known_extensions: self.known_extensions.clone(),
-            use_gzip: self.static_gzip_enabled == true,
+            file_cache: self.file_cache.clone(),
+            static_path: self.static_path.clone(),
+            static_enabled: self.static_enabled,
+            not_found_path: self.not_found_path.clone(),
+            custom_not_found: !self.not_found_path.is_empty(),
+            use_gzip: self.static_gzip_enabled,
             etag: self.etag,
         }
     }
@@ -182,8 +765,15 @@ impl NewService for PonyBuilder {
 #[cfg(test)]
 #[allow(unused_variables, dead_code)]
 mod tests {
+    use super::StaticMount;
+    use super::super::pony::{ETag, SymlinkPolicy};
+    use super::super::source::ReadContext;
+    use hyper::StatusCode;
+    use futures::Future;
     use futures::future::ok;
     use hyper::{Response, Request};
+    use tokio_core::net::TcpStream;
+    use super::super::websocket::WebSocket;
     fn res(_req: Request) -> super::super::HyperResult {
         Box::new(
             ok(
@@ -204,12 +794,75 @@ mod tests {
         pb.use_not_found("/");
     }
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn not_found() {
         let path = "examples/public/index.html";
         let mut pb = super::PonyBuilder::new();
         pb.use_not_found(&path);
-        assert!(pb.custom_not_found, "pb.custom_not_found was not set to true");
-        assert!(pb.not_found_path == path.to_string(), "pb.not_found_path did not match");
+        assert!(pb.error_pages.get(StatusCode::NotFound).is_some(), "the 404 page was not set");
+    }
+    #[test]
+    fn error_pages() {
+        let mut pb = super::PonyBuilder::new();
+        pb.use_error_page(StatusCode::ServiceUnavailable, "examples/public/files/a.txt");
+        let page = pb.error_pages.get(StatusCode::ServiceUnavailable).unwrap();
+        assert_eq!(page.content_type, "text/plain; charset=utf-8");
+        assert!(pb.error_pages.get(StatusCode::NotFound).is_none());
+    }
+    #[test]
+    #[should_panic]
+    fn error_page_failed() {
+        let mut pb = super::PonyBuilder::new();
+        pb.use_error_page(StatusCode::InternalServerError, "junk.html");
+    }
+    #[test]
+    fn try_variants() {
+        let mut pb = super::PonyBuilder::new();
+        pb.try_use_static("junk/")
+            .try_use_not_found("junk.html")
+            .try_use_archive("junk.zip")
+            .try_add_static_layer("examples/public")
+            .get("/get", res)
+            .get("/get", res)
+            .post("/get", res)
+            .try_use_static_mount(StaticMount::new("/", "examples/public"))
+            .try_use_htpasswd("/", "junk", "junk.htpasswd");
+        let err = pb.build().err().expect("build did not fail");
+        assert_eq!(err.problems.len(), 6);
+        assert!(matches!(err.problems[0], super::ConfigProblem::Source(_)));
+        assert!(matches!(err.problems[1], super::ConfigProblem::ErrorPage { status: StatusCode::NotFound, .. }));
+        assert!(matches!(err.problems[2], super::ConfigProblem::Archive { .. }));
+        assert_eq!(err.problems[3], super::ConfigProblem::ConflictingRoute { method: super::Method::Get, path: String::from("/get") });
+        assert!(matches!(err.problems[4], super::ConfigProblem::Htpasswd { .. }));
+        assert_eq!(err.problems[5], super::ConfigProblem::ConflictingMount(String::from("/")));
+    }
+    #[test]
+    fn metrics_conflict() {
+        let mut pb = super::PonyBuilder::new();
+        pb.get("/metrics", res)
+            .use_metrics("/metrics");
+        let err = pb.build().err().expect("build did not fail");
+        assert_eq!(err.problems, vec![super::ConfigProblem::ConflictingRoute { method: super::Method::Get, path: String::from("/metrics") }]);
+    }
+    fn ws(_req: Request, _ws: WebSocket<TcpStream>) -> Box<dyn Future<Item = (), Error = ()>> {
+        Box::new(ok(()))
+    }
+    #[test]
+    fn websocket_conflict() {
+        let mut pb = super::PonyBuilder::new();
+        pb.get("/ws", res)
+            .websocket("/ws", ws);
+        let err = pb.build().err().expect("build did not fail");
+        assert_eq!(err.problems, vec![super::ConfigProblem::ConflictingRoute { method: super::Method::Get, path: String::from("/ws") }]);
+    }
+    #[test]
+    fn build() {
+        let mut pb = super::PonyBuilder::new();
+        pb.try_use_static("examples/public")
+            .try_use_not_found("examples/public/404.html")
+            .get("/get", res)
+            .post("/get", res);
+        assert!(pb.build().is_ok());
     }
     #[test]
     #[should_panic]
@@ -224,6 +877,7 @@ mod tests {
         pb.use_static("examples/public/index.html");
     }
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn static_test() {
         let mut pb = super::PonyBuilder::new();
         let path = "examples/public/";
@@ -232,6 +886,21 @@ mod tests {
         assert!(pb.static_path == path, "pb.static_path does not match");
     }
     #[test]
+    #[allow(deprecated)]
+    fn deprecated_fields() {
+        let mut pb = super::PonyBuilder::new();
+        pb.use_static("examples/public/")
+            .use_static_gzip()
+            .use_not_found("examples/public/404.html")
+            .use_etag(ETag::Sha1);
+        let pony = pb.done();
+        assert!(pony.static_enabled && pony.static_path == "examples/public/");
+        assert!(pony.custom_not_found && pony.not_found_path == "examples/public/404.html");
+        assert!(pony.use_gzip);
+        assert_eq!(pony.etag, ETag::Sha1);
+        assert_eq!(pony.mounts[0].etag, pony.etag);
+    }
+    #[test]
     fn get_test() {
         let mut pb = super::PonyBuilder::new();
         pb.get("/get", res);
@@ -288,6 +957,52 @@ mod tests {
         assert!(!pb.known_extensions.contains("html"));
     }
     #[test]
+    fn autoindex() {
+        let mut pb = super::PonyBuilder::new();
+        assert!(!pb.autoindex_enabled, "pb.autoindex_enabled defaulted to true");
+        pb.use_autoindex(true);
+        assert!(pb.autoindex_enabled, "pb.autoindex_enabled was not set to true");
+        assert!(pb.autoindex_hide_dotfiles, "pb.autoindex_hide_dotfiles was not set to true");
+    }
+    #[test]
+    fn spa_fallback() {
+        let mut pb = super::PonyBuilder::new();
+        assert!(!pb.spa_fallback_enabled, "pb.spa_fallback_enabled defaulted to true");
+        pb.use_spa_fallback();
+        assert!(pb.spa_fallback_enabled, "pb.spa_fallback_enabled was not set to true");
+    }
+    #[test]
+    fn static_mounts() {
+        let mut pb = super::PonyBuilder::new();
+        pb.use_static_mount(StaticMount::new("/a", "examples/public"))
+            .use_static("examples/public")
+            .use_static_mount(StaticMount::new("/a/b", "examples/public/files"));
+        let prefixes: Vec<String> = pb.done().mounts.into_iter().map(|m| m.prefix).collect();
+        assert!(prefixes == vec!["/a/b", "/a", "/"], "mounts were not sorted longest prefix first");
+    }
+    #[test]
+    fn static_layers() {
+        let mut pb = super::PonyBuilder::new();
+        pb.use_static("examples/public/files")
+            .add_static_layer("examples/public");
+        let mounts = pb.done().mounts;
+        let cx = ReadContext { etag: ETag::None, symlinks: SymlinkPolicy::default(), cache: None };
+        let found: Vec<bool> = mounts[0].layers.iter().map(|l| l.metadata("a.txt", &cx).is_ok()).collect();
+        assert!(found == vec![true, false], "layers were not in order");
+    }
+    #[test]
+    #[should_panic]
+    fn static_layer_failed() {
+        let mut pb = super::PonyBuilder::new();
+        pb.add_static_layer("junk/");
+    }
+    #[test]
+    #[should_panic]
+    fn static_mount_failed() {
+        let mut pb = super::PonyBuilder::new();
+        pb.use_static_mount(StaticMount::new("/junk", "junk/"));
+    }
+    #[test]
     fn chain_test() {
         let mut pb = super::PonyBuilder::new();
         pb.use_static("examples/public/")
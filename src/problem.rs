@@ -0,0 +1,213 @@
+use std::fmt;
+use std::sync::Arc;
+
+use futures::Future;
+
+use hyper::{Error, Method, StatusCode};
+use hyper::server::{Request, Response};
+use hyper::header::{ContentLength, ContentType};
+
+use super::{Callback, HyperResult};
+use super::escape::json_string;
+
+///An error returned by a fallible handler, the default
+/// response is an RFC 7807 `application/problem+json` body
+/// built from the other methods
+pub trait ResponseError: fmt::Debug {
+    ///The status to respond with
+    fn status(&self) -> StatusCode {
+        StatusCode::InternalServerError
+    }
+    ///A short summary of the problem, defaults
+    /// to the status' reason phrase
+    fn title(&self) -> String {
+        self.status().canonical_reason().unwrap_or("Unknown").to_string()
+    }
+    ///An explanation specific to this occurrence of the problem
+    fn detail(&self) -> Option<String> {
+        None
+    }
+    ///A uri identifying the type of problem
+    fn problem_type(&self) -> String {
+        String::from("about:blank")
+    }
+    ///The response sent for this error
+    fn response(&self) -> Response {
+        problem_response(self)
+    }
+}
+
+///A ready made `ResponseError`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem {
+    pub status: StatusCode,
+    pub title: Option<String>,
+    pub detail: Option<String>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode) -> Problem {
+        Problem {
+            status,
+            title: None,
+            detail: None,
+        }
+    }
+    pub fn with_title(mut self, title: &str) -> Self {
+        self.title = Some(title.to_string());
+        self
+    }
+    pub fn with_detail(mut self, detail: &str) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+}
+
+impl ResponseError for Problem {
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+    fn title(&self) -> String {
+        match self.title {
+            Some(ref title) => title.clone(),
+            None => self.status.canonical_reason().unwrap_or("Unknown").to_string(),
+        }
+    }
+    fn detail(&self) -> Option<String> {
+        self.detail.clone()
+    }
+}
+
+///Render an error as `application/problem+json`
+pub fn problem_response<E: ResponseError + ?Sized>(err: &E) -> Response {
+    let status = err.status();
+    let mut body = format!("{{\"type\": {}, \"title\": {}, \"status\": {}",
+                           json_string(&err.problem_type()),
+                           json_string(&err.title()),
+                           u16::from(status));
+    if let Some(detail) = err.detail() {
+        body += &format!(", \"detail\": {}", json_string(&detail));
+    }
+    body.push('}');
+    Response::new()
+        .with_status(status)
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(ContentType("application/problem+json".parse().expect("invalid content type")))
+        .with_body(body)
+}
+
+///What a fallible handler returns, a response or
+/// a `ResponseError` once the request has been handled
+pub type FallibleResult<E> = Box<dyn Future<Item = Response, Error = E>>;
+
+///A fallible handler with the error type erased
+pub type FallibleCallback = Arc<dyn Fn(Request) -> FallibleResult<Box<dyn ResponseError>> + Send + Sync>;
+
+///Called with the method, path and error whenever a fallible
+/// handler fails, returning `Some` replaces the error's response
+pub type ErrorHook = fn(&Method, &str, &dyn ResponseError) -> Option<Response>;
+
+///Called with the method, path and panic message whenever
+/// a handler panics
+pub type PanicHook = fn(&Method, &str, &str);
+
+///A route's handler
+#[derive(Clone)]
+pub enum Handler {
+    Callback(Callback),
+    Fallible(FallibleCallback),
+}
+
+impl Handler {
+    ///Wrap a handler returning any `ResponseError`
+    pub fn fallible<E: ResponseError + 'static>(cb: fn(Request) -> FallibleResult<E>) -> Handler {
+        Handler::Fallible(Arc::new(move |req| {
+            Box::new(cb(req).map_err(|e| Box::new(e) as Box<dyn ResponseError>))
+        }))
+    }
+    ///Run this handler, errors are passed to the hook
+    /// before being turned into a response
+    pub fn call(&self, req: Request, hook: Option<ErrorHook>) -> HyperResult {
+        match *self {
+            Handler::Callback(cb) => cb(req),
+            Handler::Fallible(ref cb) => {
+                let method = req.method().clone();
+                let path = req.path().to_string();
+                Box::new(cb(req).then(move |res| -> Result<Response, Error> {
+                    Ok(match res {
+                        Ok(res) => res,
+                        Err(e) => hook.and_then(|hook| hook(&method, &path, &*e))
+                            .unwrap_or_else(|| e.response()),
+                    })
+                }))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+    use hyper::Uri;
+    use std::str::FromStr;
+
+    #[derive(Debug)]
+    struct Missing;
+
+    impl ResponseError for Missing {
+        fn status(&self) -> StatusCode {
+            StatusCode::NotFound
+        }
+    }
+
+    fn body(res: Response) -> String {
+        String::from_utf8(res.body().concat2().wait().unwrap().to_vec()).unwrap()
+    }
+
+    fn lookup(req: Request) -> FallibleResult<Missing> {
+        let found = req.path() == "/found";
+        Box::new(req.body().concat2().map_err(|_| Missing).and_then(move |body| {
+            if found && !body.is_empty() {
+                Ok(Response::new())
+            } else {
+                Err(Missing)
+            }
+        }))
+    }
+
+    fn hook(method: &Method, path: &str, err: &dyn ResponseError) -> Option<Response> {
+        Some(Response::new().with_status(err.status()).with_body(format!("{} {}", method, path)))
+    }
+
+    fn call(handler: &Handler, path: &str, hook: Option<ErrorHook>) -> Response {
+        let mut req = Request::new(Method::Post, Uri::from_str(path).unwrap());
+        req.set_body("name=pony");
+        handler.call(req, hook).wait().unwrap()
+    }
+
+    #[test]
+    fn problem_json() {
+        let res = problem_response(&Problem::new(StatusCode::BadRequest).with_detail("name is \"required\""));
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        assert_eq!(res.headers().get::<ContentType>().unwrap().to_string(), "application/problem+json");
+        assert_eq!(body(res), "{\"type\": \"about:blank\", \"title\": \"Bad Request\", \"status\": 400, \"detail\": \"name is \\\"required\\\"\"}");
+    }
+
+    #[test]
+    fn fallible() {
+        let handler = Handler::fallible(lookup);
+        assert_eq!(call(&handler, "/found", None).status(), StatusCode::Ok);
+        let res = call(&handler, "/lost", None);
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(body(res), "{\"type\": \"about:blank\", \"title\": \"Not Found\", \"status\": 404}");
+    }
+
+    #[test]
+    fn error_hook() {
+        let handler = Handler::fallible(lookup);
+        let res = call(&handler, "/lost", Some(hook));
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(body(res), "POST /lost");
+    }
+}
@@ -0,0 +1,11 @@
+use rand::{OsRng, Rng};
+
+///16 bytes from the system's random source, panics if
+/// it can't be read since every use of these is a secret
+pub fn bytes() -> [u8; 16] {
+    let mut bytes = [0; 16];
+    OsRng::new()
+        .expect("The system's random source is unavailable")
+        .fill_bytes(&mut bytes);
+    bytes
+}
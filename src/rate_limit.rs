@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::server::{Request, Response};
+
+use super::scope::{Scope, Scoped};
+
+///Builds the key a request is limited by, requests
+/// without a key are not limited
+pub type KeyFn = fn(&Request) -> Option<String>;
+
+///How many requests a client may make, `requests` are allowed
+/// each `period` with bursts of up to `burst` requests
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub requests: u32,
+    pub period: Duration,
+    pub burst: u32,
+}
+
+impl Quota {
+    pub fn new(requests: u32, period: Duration) -> Quota {
+        Quota {
+            requests,
+            period,
+            burst: requests,
+        }
+    }
+    pub fn per_second(requests: u32) -> Quota {
+        Quota::new(requests, Duration::from_secs(1))
+    }
+    pub fn per_minute(requests: u32) -> Quota {
+        Quota::new(requests, Duration::from_secs(60))
+    }
+    ///Allow up to `burst` requests at once
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+    ///Tokens added to a bucket per second
+    fn rate(&self) -> f64 {
+        self.requests as f64 / secs(self.period).max(0.001)
+    }
+    ///How long a bucket holding `tokens` takes to fill
+    fn time_to_fill(&self, tokens: f64) -> Duration {
+        duration((self.burst as f64 - tokens).max(0.0) / self.rate())
+    }
+}
+
+///The result of taking a token from a bucket
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    ///Time until the bucket is full again
+    pub reset: Duration,
+    ///Time until a refused client may try again
+    pub retry_after: Option<Duration>,
+}
+
+impl Decision {
+    ///Add the `RateLimit-*` headers and, when refused, `Retry-After`
+    pub fn apply(&self, res: Response) -> Response {
+        let mut res = res;
+        {
+            let headers = res.headers_mut();
+            headers.set_raw("RateLimit-Limit", self.limit.to_string());
+            headers.set_raw("RateLimit-Remaining", self.remaining.to_string());
+            headers.set_raw("RateLimit-Reset", ceil_secs(self.reset).to_string());
+            if let Some(retry_after) = self.retry_after {
+                headers.set_raw("Retry-After", ceil_secs(retry_after).max(1).to_string());
+            }
+        }
+        res
+    }
+}
+
+///Where token buckets are kept, implement this to share
+/// limits between processes
+pub trait RateLimitBackend: Send + Sync {
+    ///Take one token from the bucket for `key`
+    fn take(&self, key: &str, quota: &Quota) -> Decision;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    full_at: Instant,
+}
+
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    swept: Instant,
+}
+
+///Buckets kept in this process, buckets that have filled
+/// back up are dropped every `sweep_interval`
+pub struct MemoryBackend {
+    buckets: Mutex<Buckets>,
+    sweep_interval: Duration,
+}
+
+impl MemoryBackend {
+    pub fn new(sweep_interval: Duration) -> MemoryBackend {
+        MemoryBackend {
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                swept: Instant::now(),
+            }),
+            sweep_interval,
+        }
+    }
+    ///The number of buckets being kept
+    pub fn len(&self) -> usize {
+        self.buckets.lock().map(|b| b.buckets.len()).unwrap_or(0)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn take_at(&self, key: &str, quota: &Quota, now: Instant) -> Decision {
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if now.duration_since(buckets.swept) >= self.sweep_interval {
+            buckets.buckets.retain(|_, bucket| bucket.full_at > now);
+            buckets.swept = now;
+        }
+        let bucket = buckets.buckets.entry(key.to_string()).or_insert_with(|| Bucket {
+            tokens: quota.burst as f64,
+            updated: now,
+            full_at: now,
+        });
+        let elapsed = secs(now.duration_since(bucket.updated));
+        bucket.tokens = (bucket.tokens + elapsed * quota.rate()).min(quota.burst as f64);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let reset = quota.time_to_fill(bucket.tokens);
+        bucket.full_at = now + reset;
+        Decision {
+            allowed,
+            limit: quota.burst,
+            remaining: bucket.tokens.floor() as u32,
+            reset,
+            retry_after: if allowed { None } else { Some(duration((1.0 - bucket.tokens) / quota.rate())) },
+        }
+    }
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        MemoryBackend::new(Duration::from_secs(60))
+    }
+}
+
+impl RateLimitBackend for MemoryBackend {
+    fn take(&self, key: &str, quota: &Quota) -> Decision {
+        self.take_at(key, quota, Instant::now())
+    }
+}
+
+///What a request is limited by
+#[derive(Clone, Copy)]
+pub enum RateKey {
+    ///The client's ip address
+    RemoteAddr,
+    Custom(KeyFn),
+}
+
+///A quota and the key and backend used to enforce it
+#[derive(Clone)]
+pub struct RateLimit {
+    pub quota: Quota,
+    pub key: RateKey,
+    pub backend: Arc<dyn RateLimitBackend>,
+}
+
+impl RateLimit {
+    ///Limit each remote address, buckets are kept in memory
+    pub fn new(quota: Quota) -> RateLimit {
+        RateLimit {
+            quota,
+            key: RateKey::RemoteAddr,
+            backend: Arc::new(MemoryBackend::default()),
+        }
+    }
+    ///Limit by a key built from the request, such as an api key header
+    pub fn keyed_by(mut self, key: KeyFn) -> Self {
+        self.key = RateKey::Custom(key);
+        self
+    }
+    ///Keep buckets somewhere other than this process' memory
+    pub fn with_backend(mut self, backend: Arc<dyn RateLimitBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+}
+
+///Every configured limit, a request is held to the most
+/// specific one that matches it
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    limits: Scoped<RateLimit>,
+}
+
+impl RateLimits {
+    pub fn new() -> RateLimits {
+        RateLimits::default()
+    }
+    pub fn add(&mut self, scope: Scope, limit: RateLimit) {
+        self.limits.add(scope, limit);
+    }
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+    ///Take a token for a request, `None` when no limit applies
+    /// or the request has no key
+    pub fn check(&self, req: &Request, remote_addr: Option<SocketAddr>) -> Option<Decision> {
+        let (scope, limit) = self.limits.find(req.method(), req.path())?;
+        let key = match limit.key {
+            //hyper only knows the remote address when it was
+            //accepted through `Http::bind`
+            #[allow(deprecated)]
+            RateKey::RemoteAddr => req.remote_addr().or(remote_addr).map(|addr| addr.ip().to_string()),
+            RateKey::Custom(key) => key(req),
+        }?;
+        let scope = match *scope {
+            Scope::Route(ref method, ref path) => format!("{} {}", method, path),
+            Scope::Prefix(ref prefix) => prefix.clone(),
+        };
+        Some(limit.backend.take(&format!("{}|{}", scope, key), &limit.quota))
+    }
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+fn duration(secs: f64) -> Duration {
+    Duration::from_millis((secs * 1000.0).ceil() as u64)
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + if duration.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, Uri};
+    use std::str::FromStr;
+
+    #[test]
+    fn bucket() {
+        let backend = MemoryBackend::default();
+        let quota = Quota::per_second(2);
+        let now = Instant::now();
+        assert_eq!(backend.take_at("a", &quota, now).remaining, 1);
+        assert!(backend.take_at("a", &quota, now).allowed);
+        let refused = backend.take_at("a", &quota, now);
+        assert!(!refused.allowed);
+        assert_eq!(refused.retry_after, Some(Duration::from_millis(500)));
+        assert_eq!(refused.reset, Duration::from_secs(1));
+        assert!(backend.take_at("b", &quota, now).allowed);
+        assert!(backend.take_at("a", &quota, now + Duration::from_millis(500)).allowed);
+    }
+
+    #[test]
+    fn burst() {
+        let backend = MemoryBackend::default();
+        let quota = Quota::per_minute(60).with_burst(3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(backend.take_at("a", &quota, now).allowed);
+        }
+        assert_eq!(backend.take_at("a", &quota, now).retry_after, Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn sweep() {
+        let backend = MemoryBackend::new(Duration::from_secs(10));
+        let quota = Quota::per_second(1);
+        let now = Instant::now();
+        backend.take_at("a", &quota, now);
+        backend.take_at("b", &quota, now + Duration::from_millis(9500));
+        assert_eq!(backend.len(), 2);
+        backend.take_at("c", &quota, now + Duration::from_secs(10));
+        assert_eq!(backend.len(), 2);
+    }
+
+    #[test]
+    fn headers() {
+        let decision = Decision {
+            allowed: false,
+            limit: 10,
+            remaining: 0,
+            reset: Duration::from_millis(1500),
+            retry_after: Some(Duration::from_millis(100)),
+        };
+        let res = decision.apply(Response::new());
+        let header = |name| String::from_utf8(res.headers().get_raw(name).unwrap().one().unwrap().to_vec()).unwrap();
+        assert_eq!(header("RateLimit-Limit"), "10");
+        assert_eq!(header("RateLimit-Remaining"), "0");
+        assert_eq!(header("RateLimit-Reset"), "2");
+        assert_eq!(header("Retry-After"), "1");
+    }
+
+    fn api_key(req: &Request) -> Option<String> {
+        req.headers().get_raw("X-Api-Key")
+            .and_then(|raw| raw.one())
+            .map(|key| String::from_utf8_lossy(key).to_string())
+    }
+
+    #[test]
+    fn scopes() {
+        let mut limits = RateLimits::new();
+        limits.add(Scope::Prefix(String::from("/api")), RateLimit::new(Quota::per_minute(1)));
+        limits.add(Scope::Route(Method::Post, String::from("/api/login")), RateLimit::new(Quota::per_minute(2)).keyed_by(api_key));
+        let addr = Some("127.0.0.1:5000".parse().unwrap());
+        let get = |path| Request::new(Method::Get, Uri::from_str(path).unwrap());
+        assert!(limits.check(&get("/apis"), addr).is_none());
+        assert!(limits.check(&get("/api/a"), addr).unwrap().allowed);
+        assert!(!limits.check(&get("/api/b"), addr).unwrap().allowed);
+        assert!(limits.check(&get("/api/b"), None).is_none());
+        let mut login = Request::new(Method::Post, Uri::from_str("/api/login").unwrap());
+        assert!(limits.check(&login, addr).is_none());
+        login.headers_mut().set_raw("X-Api-Key", "k");
+        assert_eq!(limits.check(&login, addr).unwrap().limit, 2);
+    }
+}
@@ -0,0 +1,103 @@
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::server::Request;
+use hyper::header::Headers;
+
+use sha1::{Sha1, Digest};
+
+///The header request ids are read from and echoed back in
+pub const HEADER: &str = "X-Request-Id";
+
+///Where a request's id comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RequestIdPolicy {
+    ///Always generate a new id, any incoming header is replaced
+    Generate,
+    ///Keep a well formed incoming `X-Request-Id`, only use this
+    /// behind a proxy that sets or strips the header
+    TrustIncoming,
+}
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+///The id of a request handled by a `Pony` with request ids enabled
+pub fn get(req: &Request) -> Option<&str> {
+    from_headers(req.headers())
+}
+
+///The request id in a set of headers
+pub fn from_headers(headers: &Headers) -> Option<&str> {
+    headers.get_raw(HEADER)
+        .and_then(|raw| raw.one())
+        .and_then(|line| str::from_utf8(line).ok())
+}
+
+///Give a request an id according to the policy, the id is
+/// stored in the request's `X-Request-Id` header and returned
+pub fn assign(req: &mut Request, policy: RequestIdPolicy) -> String {
+    let incoming = match policy {
+        RequestIdPolicy::TrustIncoming => get(req).filter(|id| is_valid(id)).map(|id| id.to_string()),
+        RequestIdPolicy::Generate => None,
+    };
+    let id = incoming.unwrap_or_else(generate);
+    req.headers_mut().set_raw(HEADER, id.clone());
+    id
+}
+
+///32 hex characters unique to this process, built
+/// from the time, process id and a counter
+pub fn generate() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut sh = Sha1::default();
+    sh.input(format!("{}-{}-{}", nanos, ::std::process::id(), count).as_bytes());
+    sh.result().iter().take(16).map(|b| format!("{:02x}", b)).collect()
+}
+
+///Incoming ids must be 1 to 128 visible ascii characters
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, Uri};
+    use std::str::FromStr;
+
+    fn request(id: Option<&str>) -> Request {
+        let mut req = Request::new(Method::Get, Uri::from_str("/").unwrap());
+        if let Some(id) = id {
+            req.headers_mut().set_raw(HEADER, id.to_string());
+        }
+        req
+    }
+
+    #[test]
+    fn generated() {
+        let first = generate();
+        assert_eq!(first.len(), 32);
+        assert_ne!(first, generate());
+    }
+
+    #[test]
+    fn trusted() {
+        let mut req = request(Some("abc-123"));
+        assert_eq!(assign(&mut req, RequestIdPolicy::TrustIncoming), "abc-123");
+        assert_eq!(get(&req), Some("abc-123"));
+        let mut req = request(Some("has space"));
+        assert_ne!(assign(&mut req, RequestIdPolicy::TrustIncoming), "has space");
+    }
+
+    #[test]
+    fn untrusted() {
+        let mut req = request(Some("abc-123"));
+        let id = assign(&mut req, RequestIdPolicy::Generate);
+        assert_ne!(id, "abc-123");
+        assert_eq!(get(&req), Some(id.as_str()));
+    }
+}
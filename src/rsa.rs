@@ -0,0 +1,52 @@
+use ring::signature::{RsaPublicKeyComponents, RSA_PKCS1_2048_8192_SHA256};
+
+///An RSA public key, only able to check signatures
+#[derive(Clone, Debug, PartialEq)]
+pub struct RsaPublicKey {
+    ///The big endian modulus without leading zeros
+    n: Vec<u8>,
+    e: Vec<u8>,
+}
+
+impl RsaPublicKey {
+    ///Build a key from its big endian modulus and exponent,
+    /// keys shorter than 2048 bits are refused
+    pub fn new(n: &[u8], e: &[u8]) -> Result<RsaPublicKey, String> {
+        let n = trim(n);
+        let e = trim(e);
+        let bits = n.first().map(|b| n.len() * 8 - b.leading_zeros() as usize).unwrap_or(0);
+        if bits < 2048 {
+            return Err(format!("RSA keys must be at least 2048 bits, this one is {}", bits))
+        }
+        if n[n.len() - 1] & 1 == 0 || e.is_empty() || e == [1] {
+            return Err(String::from("Invalid RSA key"))
+        }
+        Ok(RsaPublicKey { n: n.to_vec(), e: e.to_vec() })
+    }
+
+    ///Check an RSASSA-PKCS1-v1_5 signature over the SHA-256 hash of `message`
+    pub fn verify_sha256(&self, message: &[u8], signature: &[u8]) -> bool {
+        let key = RsaPublicKeyComponents { n: &self.n, e: &self.e };
+        key.verify(&RSA_PKCS1_2048_8192_SHA256, message, signature).is_ok()
+    }
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new() {
+        assert!(RsaPublicKey::new(&[0xff; 128], &[1, 0, 1]).is_err());
+        let mut n = vec![0];
+        n.extend_from_slice(&[0xff; 256]);
+        assert_eq!(RsaPublicKey::new(&n, &[0, 1, 0, 1]).unwrap(), RsaPublicKey { n: vec![0xff; 256], e: vec![1, 0, 1] });
+        assert!(RsaPublicKey::new(&[0xfe; 256], &[1, 0, 1]).is_err());
+        assert!(RsaPublicKey::new(&[0xff; 256], &[1]).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use hyper::StatusCode;
+
+use super::pony::SymlinkPolicy;
+
+///Split a decoded request path into segments that are
+/// safe to join onto a static root.
+/// Any attempt to climb out of the root (`..`, back slashes
+/// or nul bytes) is Forbidden, dotfiles are NotFound
+/// unless `allow_dotfiles` is true
+pub fn segments(path: &str, allow_dotfiles: bool) -> Result<Vec<&str>, StatusCode> {
+    let mut ret = vec!();
+    for segment in path.split('/') {
+        if segment.is_empty() || segment == "." {
+            continue;
+        }
+        if segment == ".." || segment.contains('\\') || segment.contains('\0')
+            || (cfg!(windows) && segment.contains(':')) {
+            return Err(StatusCode::Forbidden);
+        }
+        if !allow_dotfiles && segment.starts_with('.') {
+            return Err(StatusCode::NotFound);
+        }
+        ret.push(segment);
+    }
+    Ok(ret)
+}
+
+///Verify that a path built from `segments` respects
+/// the symlink policy for the static root
+pub fn check(root: &Path, candidate: &Path, policy: SymlinkPolicy) -> Result<(), StatusCode> {
+    if fs::symlink_metadata(candidate).is_err() {
+        return Err(StatusCode::NotFound);
+    }
+    match policy {
+        SymlinkPolicy::Follow => Ok(()),
+        SymlinkPolicy::WithinRoot => {
+            let root = fs::canonicalize(root).map_err(|_| StatusCode::NotFound)?;
+            let resolved = fs::canonicalize(candidate).map_err(|_| StatusCode::NotFound)?;
+            if resolved.starts_with(&root) {
+                Ok(())
+            } else {
+                Err(StatusCode::Forbidden)
+            }
+        },
+        SymlinkPolicy::Deny => {
+            let relative = candidate.strip_prefix(root).map_err(|_| StatusCode::Forbidden)?;
+            let mut current = PathBuf::from(root);
+            for part in relative.components() {
+                current.push(part);
+                match fs::symlink_metadata(&current) {
+                    Ok(ref md) if md.file_type().is_symlink() => return Err(StatusCode::Forbidden),
+                    Ok(_) => (),
+                    Err(_) => return Err(StatusCode::NotFound),
+                }
+            }
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_segments() {
+        assert_eq!(segments("/a/./b//c.txt", false), Ok(vec!["a", "b", "c.txt"]));
+    }
+
+    #[test]
+    fn traversal_segments() {
+        assert_eq!(segments("/../etc/passwd", false), Err(StatusCode::Forbidden));
+        assert_eq!(segments("/a/..\\..\\b", false), Err(StatusCode::Forbidden));
+        assert_eq!(segments("/a\0.txt", false), Err(StatusCode::Forbidden));
+    }
+
+    #[test]
+    fn dotfile_segments() {
+        assert_eq!(segments("/.git/config", false), Err(StatusCode::NotFound));
+        assert_eq!(segments("/.git/config", true), Ok(vec![".git", "config"]));
+    }
+
+    #[test]
+    fn symlinks() {
+        let root = Path::new("examples/public");
+        let inside = root.join("links").join("inside.txt");
+        let outside = root.join("links").join("outside.toml");
+        assert_eq!(check(root, &inside, SymlinkPolicy::WithinRoot), Ok(()));
+        assert_eq!(check(root, &outside, SymlinkPolicy::WithinRoot), Err(StatusCode::Forbidden));
+        assert_eq!(check(root, &inside, SymlinkPolicy::Deny), Err(StatusCode::Forbidden));
+        assert_eq!(check(root, &outside, SymlinkPolicy::Follow), Ok(()));
+        assert_eq!(check(root, &root.join("missing.txt"), SymlinkPolicy::Follow), Err(StatusCode::NotFound));
+    }
+}
@@ -0,0 +1,109 @@
+use hyper::Method;
+
+use percent_encoding::percent_decode;
+
+///The requests a setting applies to
+#[derive(Clone, Debug, PartialEq)]
+pub enum Scope {
+    Route(Method, String),
+    ///Every path under a prefix, including static files
+    Prefix(String),
+}
+
+impl Scope {
+    ///Prefixes are matched against the decoded path with empty
+    /// and `.` segments removed, the same way static files are found
+    pub fn matches(&self, method: &Method, path: &str) -> bool {
+        match *self {
+            Scope::Route(ref m, ref p) => m == method && p == path,
+            Scope::Prefix(ref prefix) => {
+                let prefix = prefix.trim_end_matches('/');
+                let path = normalize(path);
+                path.starts_with(prefix)
+                    && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+            },
+        }
+    }
+    ///Routes win over prefixes, then longer prefixes over shorter
+    fn specificity(&self) -> usize {
+        match *self {
+            Scope::Route(_, _) => usize::MAX,
+            Scope::Prefix(ref prefix) => prefix.len(),
+        }
+    }
+}
+
+fn normalize(path: &str) -> String {
+    let decoded = percent_decode(path.as_bytes()).decode_utf8_lossy();
+    let mut ret = String::with_capacity(decoded.len());
+    for segment in decoded.split('/').filter(|s| !s.is_empty() && *s != ".") {
+        ret.push('/');
+        ret.push_str(segment);
+    }
+    if ret.is_empty() {
+        ret.push('/');
+    }
+    ret
+}
+
+///Settings that each apply to a scope, a request
+/// uses the most specific one that matches it
+#[derive(Clone, Debug)]
+pub struct Scoped<T> {
+    items: Vec<(Scope, T)>,
+}
+
+impl<T> Scoped<T> {
+    pub fn new() -> Scoped<T> {
+        Scoped { items: vec!() }
+    }
+    ///Add or replace the setting for a scope
+    pub fn add(&mut self, scope: Scope, item: T) {
+        self.items.retain(|(s, _)| *s != scope);
+        self.items.push((scope, item));
+    }
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+    pub fn find(&self, method: &Method, path: &str) -> Option<(&Scope, &T)> {
+        self.items.iter()
+            .filter(|(scope, _)| scope.matches(method, path))
+            .max_by_key(|(scope, _)| scope.specificity())
+            .map(|(scope, item)| (scope, item))
+    }
+}
+
+impl<T> Default for Scoped<T> {
+    fn default() -> Self {
+        Scoped::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes() {
+        let scope = Scope::Prefix(String::from("/private/"));
+        assert!(scope.matches(&Method::Get, "/private"));
+        assert!(scope.matches(&Method::Post, "/private/a"));
+        assert!(scope.matches(&Method::Get, "/%70rivate/a"));
+        assert!(scope.matches(&Method::Get, "//./private/a"));
+        assert!(!scope.matches(&Method::Get, "/privately"));
+        assert!(Scope::Prefix(String::from("/")).matches(&Method::Get, "/"));
+    }
+
+    #[test]
+    fn most_specific() {
+        let mut scoped = Scoped::new();
+        scoped.add(Scope::Prefix(String::from("/")), 1);
+        scoped.add(Scope::Prefix(String::from("/api")), 2);
+        scoped.add(Scope::Route(Method::Post, String::from("/api/login")), 3);
+        assert_eq!(scoped.find(&Method::Get, "/a").map(|(_, i)| *i), Some(1));
+        assert_eq!(scoped.find(&Method::Get, "/api/login").map(|(_, i)| *i), Some(2));
+        assert_eq!(scoped.find(&Method::Post, "/api/login").map(|(_, i)| *i), Some(3));
+        scoped.add(Scope::Prefix(String::from("/api")), 4);
+        assert_eq!(scoped.find(&Method::Get, "/api").map(|(_, i)| *i), Some(4));
+    }
+}
@@ -0,0 +1,132 @@
+use std::str;
+
+use base64;
+
+use hyper::server::{Request, Response};
+
+use super::random;
+
+///The request header handlers read the CSP nonce from,
+/// any value sent by the client is removed
+pub const NONCE_HEADER: &str = "X-Csp-Nonce";
+
+///Replaced by a new nonce for each request in any header value
+pub const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+///Headers added to every response that doesn't already
+/// have them, to opt in to browser protections
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecurityHeaders {
+    headers: Vec<(String, String)>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders::new()
+    }
+}
+
+impl SecurityHeaders {
+    ///A strict set of headers, scripts and styles have to come
+    /// from this origin or carry the request's nonce
+    pub fn new() -> SecurityHeaders {
+        SecurityHeaders::empty()
+            .with_hsts(31_536_000, true)
+            .with_csp("default-src 'self'; script-src 'self' 'nonce-{nonce}'; style-src 'self' 'nonce-{nonce}'; object-src 'none'; base-uri 'self'; frame-ancestors 'none'")
+            .with("X-Content-Type-Options", "nosniff")
+            .with_frame_options("DENY")
+            .with_referrer_policy("strict-origin-when-cross-origin")
+            .with_permissions_policy("camera=(), microphone=(), geolocation=()")
+    }
+    ///No headers, for routes that need none of them
+    pub fn empty() -> SecurityHeaders {
+        SecurityHeaders { headers: vec!() }
+    }
+    ///Add or replace a header
+    pub fn with(mut self, name: &str, value: &str) -> Self {
+        self = self.without(name);
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+    ///Stop adding a header
+    pub fn without(mut self, name: &str) -> Self {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self
+    }
+    ///Tell browsers to only use https for `max_age` seconds
+    pub fn with_hsts(self, max_age: u64, include_subdomains: bool) -> Self {
+        let value = format!("max-age={}{}", max_age, if include_subdomains { "; includeSubDomains" } else { "" });
+        self.with("Strict-Transport-Security", &value)
+    }
+    ///`{nonce}` in the policy is replaced by the request's nonce,
+    /// which handlers can read with `security_headers::nonce`
+    pub fn with_csp(self, policy: &str) -> Self {
+        self.with("Content-Security-Policy", policy)
+    }
+    pub fn with_frame_options(self, value: &str) -> Self {
+        self.with("X-Frame-Options", value)
+    }
+    pub fn with_referrer_policy(self, value: &str) -> Self {
+        self.with("Referrer-Policy", value)
+    }
+    pub fn with_permissions_policy(self, value: &str) -> Self {
+        self.with("Permissions-Policy", value)
+    }
+    ///If a nonce has to be made for each request
+    pub fn uses_nonce(&self) -> bool {
+        self.headers.iter().any(|(_, value)| value.contains(NONCE_PLACEHOLDER))
+    }
+    ///Add these headers to a response, any it already has are left alone
+    pub fn apply(&self, res: &mut Response, nonce: Option<&str>) {
+        for (name, value) in &self.headers {
+            if res.headers().get_raw(name).is_some() {
+                continue;
+            }
+            let value = match nonce {
+                Some(nonce) => value.replace(NONCE_PLACEHOLDER, nonce),
+                None => value.clone(),
+            };
+            res.headers_mut().set_raw(name.clone(), value);
+        }
+    }
+}
+
+///A new nonce for a Content-Security-Policy
+pub fn generate_nonce() -> String {
+    base64::encode(&random::bytes())
+}
+
+///The nonce to put on inline scripts and styles
+pub fn nonce(req: &Request) -> Option<&str> {
+    req.headers().get_raw(NONCE_HEADER)
+        .and_then(|raw| raw.one())
+        .and_then(|line| str::from_utf8(line).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let headers = SecurityHeaders::new().with_csp("script-src 'nonce-{nonce}'").without("permissions-policy");
+        assert!(headers.uses_nonce());
+        let mut res = Response::new().with_header(::hyper::header::ContentType::html());
+        res.headers_mut().set_raw("X-Frame-Options", "SAMEORIGIN");
+        headers.apply(&mut res, Some("abc"));
+        let get = |name: &str| res.headers().get_raw(name).and_then(|raw| raw.one()).map(|v| str::from_utf8(v).unwrap().to_string());
+        assert_eq!(get("Content-Security-Policy"), Some(String::from("script-src 'nonce-abc'")));
+        assert_eq!(get("X-Frame-Options"), Some(String::from("SAMEORIGIN")));
+        assert_eq!(get("Strict-Transport-Security"), Some(String::from("max-age=31536000; includeSubDomains")));
+        assert_eq!(get("X-Content-Type-Options"), Some(String::from("nosniff")));
+        assert_eq!(get("Permissions-Policy"), None);
+        assert!(!SecurityHeaders::empty().with_hsts(60, false).uses_nonce());
+    }
+
+    #[test]
+    fn nonces() {
+        let (a, b) = (generate_nonce(), generate_nonce());
+        assert_eq!(a.len(), 24);
+        assert!(a != b);
+    }
+}
@@ -0,0 +1,550 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll, Sink, Stream};
+use futures::future::Either;
+
+use hyper::{Body, Chunk};
+use hyper::server::{Http, Request, Response, Service};
+use hyper::server::conn::{Connection, Parts};
+
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use super::access_log::SentBody;
+use super::pony::{Pony, SentResult};
+use super::pony_builder::PonyBuilder;
+use super::websocket::{Upgrade, WebSocket};
+
+///How long a client may take over each part of a request and
+/// how large its headers may be, a client that runs out of time
+/// has its connection closed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionLimits {
+    ///From the first byte of a request to the end of its headers,
+    /// a new connection must start sending within this time too
+    pub header_timeout: Option<Duration>,
+    ///From the end of a request's headers to the end of its body
+    pub body_timeout: Option<Duration>,
+    ///How long a kept alive connection may sit idle after a response
+    pub keep_alive_timeout: Option<Duration>,
+    ///The largest request line and headers accepted, larger
+    /// requests get a 431 and the connection is closed. Hyper
+    /// checks this against its read buffer, which grows 8KB
+    /// at a time, so the real limit can be up to 8KB lower
+    pub max_header_size: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            header_timeout: Some(Duration::from_secs(10)),
+            body_timeout: Some(Duration::from_secs(30)),
+            keep_alive_timeout: Some(Duration::from_secs(60)),
+            max_header_size: 32 * 1024,
+        }
+    }
+}
+
+///Accept connections on `addr` and answer them with the
+/// builder's routes until the process exits
+pub fn serve(builder: &PonyBuilder, addr: &SocketAddr, limits: ConnectionLimits) -> io::Result<()> {
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let listener = TcpListener::bind(addr, &handle)?;
+    let mut http = Http::<Chunk>::new();
+    http.max_buf_size(limits.max_header_size);
+    let server = listener.incoming().for_each(|(stream, remote_addr)| {
+        let mut pony = builder.done();
+        pony.remote_addr = Some(remote_addr);
+        pony.upgrades = true;
+        let tracker = Rc::new(RefCell::new(Tracker::new(limits, Instant::now())));
+        let io = Guarded { stream, tracker: tracker.clone() };
+        let service = Counted { pony, tracker: tracker.clone(), handle: handle.clone() };
+        let spawner = handle.clone();
+        let conn = Upgradable(Some(http.serve_connection(io, service)))
+            .select2(Watchdog::new(tracker, &handle)?)
+            .then(move |res| {
+                match res {
+                    Ok(Either::A((Some(parts), _))) => upgrade(parts, &spawner),
+                    Ok(Either::B((Phase::Headers, _))) => info!("closed connection from {}, request headers took too long", remote_addr),
+                    Ok(Either::B((Phase::Body, _))) => info!("closed connection from {}, request body took too long", remote_addr),
+                    Err(Either::A((::hyper::Error::TooLarge, _))) => info!("closed connection from {}, request headers too large", remote_addr),
+                    Err(Either::A((e, _))) => warn!("connection error from {}: {}", remote_addr, e),
+                    _ => (),
+                }
+                Ok(())
+            });
+        handle.spawn(conn);
+        Ok(())
+    });
+    core.run(server)
+}
+
+///Answers requests with `Pony::call_counted` so the access
+/// log has the bytes each response really sent, and tells
+/// the connection's `Tracker` where each request is
+struct Counted {
+    pony: Pony,
+    tracker: Rc<RefCell<Tracker>>,
+    handle: Handle,
+}
+
+impl Service for Counted {
+    type Request = Request;
+    type Response = Response<SentBody>;
+    type Error = ::hyper::Error;
+    type Future = SentResult;
+    fn call(&self, req: Request) -> SentResult {
+        let mut req = req;
+        let now = Instant::now();
+        match req.body_mut().take() {
+            Some(body) => {
+                self.tracker.borrow_mut().started(true, now);
+                let (tx, watched) = Body::pair();
+                req.set_body(watched);
+                let tracker = self.tracker.clone();
+                let read = tx.send_all(body.then(Ok))
+                    .then(move |_| {
+                        tracker.borrow_mut().body_read(Instant::now());
+                        Ok(())
+                    });
+                self.handle.spawn(read);
+            },
+            None => self.tracker.borrow_mut().started(false, now),
+        }
+        let tracker = self.tracker.clone();
+        self.pony.call_counted(req, Box::new(move || tracker.borrow_mut().responded(Instant::now())))
+    }
+}
+
+///Drives a connection until it is done, resolving with its
+/// parts if it ended by switching to the WebSocket protocol
+struct Upgradable(Option<Connection<Guarded, Counted>>);
+
+impl Future for Upgradable {
+    type Item = Option<Parts<Guarded, Counted>>;
+    type Error = ::hyper::Error;
+    fn poll(&mut self) -> Poll<Self::Item, ::hyper::Error> {
+        if let Some(conn) = self.0.as_mut() {
+            if conn.poll_without_shutdown()?.is_not_ready() {
+                return Ok(Async::NotReady)
+            }
+        }
+        let mut parts = match self.0.take() {
+            Some(conn) => conn.into_parts(),
+            None => return Ok(Async::Ready(None)),
+        };
+        let upgraded = parts.service.pony.upgrade.lock().map(|u| u.is_some()).unwrap_or(false);
+        if upgraded {
+            return Ok(Async::Ready(Some(parts)))
+        }
+        let _ = AsyncWrite::shutdown(&mut parts.io);
+        Ok(Async::Ready(None))
+    }
+}
+
+///Start the WebSocket handler for an upgraded connection
+fn upgrade(parts: Parts<Guarded, Counted>, handle: &Handle) {
+    let upgrade = parts.service.pony.upgrade.lock().ok().and_then(|mut u| u.take());
+    if let Some(Upgrade { handler, request }) = upgrade {
+        let socket = WebSocket::new(parts.io.stream, &parts.read_buf);
+        handle.spawn(handler(request, socket));
+    }
+}
+
+///Where a connection is in the request it is reading
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    ///Waiting for the next request after a response
+    Idle,
+    Headers,
+    Body,
+    ///The request has been read and the handler is running
+    /// or its response is being sent
+    Handling,
+}
+
+///Follows what hyper has read and sent on a connection
+/// to know which time limit applies
+#[derive(Debug)]
+struct Tracker {
+    limits: ConnectionLimits,
+    phase: Phase,
+    deadline: Option<Instant>,
+    ///A response was sent before the request's body was read
+    responded: bool,
+}
+
+impl Tracker {
+    ///A new connection gets the header timeout to start its first request
+    fn new(limits: ConnectionLimits, now: Instant) -> Tracker {
+        Tracker {
+            limits,
+            phase: Phase::Headers,
+            deadline: limits.header_timeout.map(|limit| now + limit),
+            responded: false,
+        }
+    }
+    ///Bytes were read from the client, on an idle
+    /// connection they start the next request
+    fn read(&mut self, now: Instant) {
+        if self.phase == Phase::Idle {
+            self.enter(Phase::Headers, self.limits.header_timeout, now);
+        }
+    }
+    ///Hyper has read a request's headers and is
+    /// passing it to the handler
+    fn started(&mut self, has_body: bool, now: Instant) {
+        self.responded = false;
+        if has_body {
+            self.enter(Phase::Body, self.limits.body_timeout, now);
+        } else {
+            self.enter(Phase::Handling, None, now);
+        }
+    }
+    ///The request's body has been read, or the
+    /// handler stopped reading it
+    fn body_read(&mut self, now: Instant) {
+        if self.phase != Phase::Body {
+            return
+        }
+        if self.responded {
+            self.responded = false;
+            self.enter(Phase::Idle, self.limits.keep_alive_timeout, now);
+        } else {
+            self.enter(Phase::Handling, None, now);
+        }
+    }
+    ///The whole response has been sent, the keep alive timeout
+    /// counts from here unless the request's body is still
+    /// being read, then it counts from the end of the body
+    fn responded(&mut self, now: Instant) {
+        match self.phase {
+            Phase::Body => self.responded = true,
+            Phase::Handling => self.enter(Phase::Idle, self.limits.keep_alive_timeout, now),
+            Phase::Idle | Phase::Headers => (),
+        }
+    }
+
+    fn enter(&mut self, phase: Phase, limit: Option<Duration>, now: Instant) {
+        self.phase = phase;
+        self.deadline = limit.map(|limit| now + limit);
+    }
+}
+
+///A client connection that reports what it
+/// reads to a `Tracker`
+struct Guarded {
+    stream: TcpStream,
+    tracker: Rc<RefCell<Tracker>>,
+}
+
+impl Read for Guarded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        if read > 0 {
+            self.tracker.borrow_mut().read(Instant::now());
+        }
+        Ok(read)
+    }
+}
+
+impl Write for Guarded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for Guarded {}
+
+impl AsyncWrite for Guarded {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.stream)
+    }
+}
+
+///Resolves with the phase a connection was in when its
+/// deadline passed. It shares a task with the connection
+/// so it is polled again whenever the deadline may change
+struct Watchdog {
+    tracker: Rc<RefCell<Tracker>>,
+    timeout: Timeout,
+    at: Instant,
+}
+
+impl Watchdog {
+    fn new(tracker: Rc<RefCell<Tracker>>, handle: &Handle) -> io::Result<Watchdog> {
+        let at = Instant::now();
+        Ok(Watchdog {
+            tracker,
+            timeout: Timeout::new_at(at, handle)?,
+            at,
+        })
+    }
+}
+
+impl Future for Watchdog {
+    type Item = Phase;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Phase, io::Error> {
+        let (phase, deadline) = {
+            let tracker = self.tracker.borrow();
+            (tracker.phase, tracker.deadline)
+        };
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return Ok(Async::NotReady),
+        };
+        if deadline != self.at {
+            self.at = deadline;
+            self.timeout.reset(deadline);
+        }
+        Ok(self.timeout.poll()?.map(|()| phase))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases() {
+        let now = Instant::now();
+        let mut tracker = Tracker::new(ConnectionLimits::default(), now);
+        assert_eq!(tracker.phase, Phase::Headers);
+        assert_eq!(tracker.deadline, Some(now + Duration::from_secs(10)));
+        tracker.started(false, now);
+        assert_eq!(tracker.phase, Phase::Handling);
+        assert_eq!(tracker.deadline, None);
+        //reads while handling are pipelined requests hyper
+        // will pass along once this response is sent
+        tracker.read(now);
+        assert_eq!(tracker.phase, Phase::Handling);
+        tracker.responded(now);
+        assert_eq!(tracker.phase, Phase::Idle);
+        assert_eq!(tracker.deadline, Some(now + Duration::from_secs(60)));
+        tracker.read(now);
+        assert_eq!(tracker.phase, Phase::Headers);
+        tracker.started(true, now);
+        assert_eq!(tracker.phase, Phase::Body);
+        assert_eq!(tracker.deadline, Some(now + Duration::from_secs(30)));
+        tracker.body_read(now);
+        assert_eq!(tracker.phase, Phase::Handling);
+    }
+
+    #[test]
+    fn early_responses() {
+        let now = Instant::now();
+        let mut tracker = Tracker::new(ConnectionLimits::default(), now);
+        tracker.started(true, now);
+        tracker.responded(now);
+        assert_eq!(tracker.phase, Phase::Body);
+        assert_eq!(tracker.deadline, Some(now + Duration::from_secs(30)));
+        tracker.body_read(now);
+        assert_eq!(tracker.phase, Phase::Idle);
+        assert_eq!(tracker.deadline, Some(now + Duration::from_secs(60)));
+    }
+
+    fn start(pb: PonyBuilder) -> SocketAddr {
+        let addr = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        ::std::thread::spawn(move || pb.serve(&addr).unwrap());
+        for _ in 0..100 {
+            if ::std::net::TcpStream::connect(addr).is_ok() {
+                break;
+            }
+            ::std::thread::sleep(Duration::from_millis(10));
+        }
+        addr
+    }
+
+    #[test]
+    fn served() {
+        let mut pb = PonyBuilder::new();
+        pb.use_header_timeout(Duration::from_millis(100));
+        let addr = start(pb);
+
+        let mut slow = ::std::net::TcpStream::connect(addr).unwrap();
+        slow.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n").unwrap();
+        let mut buf = vec!();
+        assert_eq!(slow.read_to_end(&mut buf).unwrap(), 0);
+
+        let mut large = ::std::net::TcpStream::connect(addr).unwrap();
+        large.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        large.write_all(&[b'a'; 40 * 1024]).unwrap();
+        let _ = large.read_to_end(&mut buf);
+        assert!(buf.starts_with(b"HTTP/1.1 431 "));
+
+        let mut split = ::std::net::TcpStream::connect(addr).unwrap();
+        for part in &["GET / HTTP/1.1\r\n", "Host: a\r\n", "Connection: close\r\n", "\r\n"] {
+            split.write_all(part.as_bytes()).unwrap();
+            ::std::thread::sleep(Duration::from_millis(10));
+        }
+        buf.clear();
+        split.read_to_end(&mut buf).unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 404 "));
+    }
+
+    fn trickle(_req: ::hyper::server::Request) -> ::HyperResult {
+        let (tx, body) = ::hyper::Body::pair();
+        ::std::thread::spawn(move || {
+            use futures::Sink;
+            let tx = tx.send(Ok(::hyper::Chunk::from("hel"))).wait().unwrap();
+            ::std::thread::sleep(Duration::from_millis(300));
+            tx.send(Ok(::hyper::Chunk::from("lo"))).wait().unwrap();
+        });
+        Box::new(::futures::future::ok(::hyper::server::Response::new().with_body(body)))
+    }
+
+    #[test]
+    fn keep_alive() {
+        let mut pb = PonyBuilder::new();
+        pb.get("/trickle", trickle)
+            .use_keep_alive_timeout(Duration::from_millis(100));
+        let addr = start(pb);
+
+        let mut client = ::std::net::TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /trickle HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert!(read_head(&mut client).starts_with("HTTP/1.1 200 "));
+        //a pause in the response longer than the keep alive timeout
+        let mut body = [0; 15];
+        client.read_exact(&mut body).unwrap();
+        assert_eq!(&body, b"3\r\nhel\r\n2\r\nlo\r\n");
+        let mut buf = vec!();
+        client.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"0\r\n\r\n");
+    }
+
+    fn read_head(stream: &mut ::std::net::TcpStream) -> String {
+        let mut head = vec!();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            head.extend_from_slice(&byte);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    fn echo_body(req: ::hyper::server::Request) -> ::HyperResult {
+        Box::new(req.body().concat2().map(|body| {
+            ::hyper::server::Response::new()
+                .with_header(::hyper::header::ContentLength(body.len() as u64))
+                .with_body(body.to_vec())
+        }))
+    }
+
+    fn streamed(_req: ::hyper::server::Request) -> ::HyperResult {
+        let (tx, body) = ::hyper::Body::pair();
+        ::std::thread::spawn(move || {
+            use futures::Sink;
+            let tx = tx.send(Ok(::hyper::Chunk::from("hel"))).wait().unwrap();
+            tx.send(Ok(::hyper::Chunk::from("lo"))).wait().unwrap();
+        });
+        Box::new(::futures::future::ok(::hyper::server::Response::new().with_body(body)))
+    }
+
+    #[test]
+    fn access_log_bytes_sent() {
+        let path = ::test_support::temp_path("served-access.log");
+        let mut pb = PonyBuilder::new();
+        pb.get("/chunked", streamed)
+            .use_access_log(::access_log::LogFormat::Common, ::access_log::LogTarget::File { path: path.clone(), max_bytes: 1024 * 1024, keep: 1 });
+        let addr = start(pb);
+        let mut client = ::std::net::TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /chunked HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+        let mut buf = vec!();
+        client.read_to_end(&mut buf).unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 200 "));
+        let contents = ::std::fs::read_to_string(&path).unwrap();
+        assert!(contents.trim_end().ends_with("\"GET /chunked HTTP/1.1\" 200 5"), "{}", contents);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn expect_continue() {
+        let mut pb = PonyBuilder::new();
+        pb.post("/echo", echo_body)
+            .use_header_timeout(Duration::from_millis(100));
+        let addr = start(pb);
+
+        let mut client = ::std::net::TcpStream::connect(addr).unwrap();
+        client.write_all(b"POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n").unwrap();
+        assert!(read_head(&mut client).starts_with("HTTP/1.1 100 "));
+        client.write_all(b"hello").unwrap();
+        assert!(read_head(&mut client).starts_with("HTTP/1.1 200 "));
+        let mut body = [0; 5];
+        client.read_exact(&mut body).unwrap();
+        assert_eq!(&body, b"hello");
+        //kept alive past the header timeout
+        ::std::thread::sleep(Duration::from_millis(300));
+        client.write_all(b"GET /missing HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+        let mut buf = vec!();
+        client.read_to_end(&mut buf).unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 404 "));
+    }
+
+    #[test]
+    fn slow_body() {
+        let mut pb = PonyBuilder::new();
+        pb.post("/echo", echo_body)
+            .use_body_timeout(Duration::from_millis(100));
+        let addr = start(pb);
+
+        let mut client = ::std::net::TcpStream::connect(addr).unwrap();
+        client.write_all(b"POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhe").unwrap();
+        let mut buf = vec!();
+        assert_eq!(client.read_to_end(&mut buf).unwrap(), 0);
+    }
+
+    fn echo(_req: ::hyper::server::Request, ws: WebSocket<TcpStream>) -> Box<dyn Future<Item = (), Error = ()>> {
+        use super::super::websocket::Message;
+        let (sink, stream) = ws.split();
+        Box::new(
+            stream.filter(|msg| matches!(*msg, Message::Text(_) | Message::Binary(_)))
+            .forward(sink)
+            .then(|_| Ok(()))
+        )
+    }
+
+    fn masked(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8, 9, 8, 7, 6];
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ [9, 8, 7, 6][i % 4]));
+        frame
+    }
+
+    #[test]
+    fn websocket() {
+        let mut pb = PonyBuilder::new();
+        pb.websocket("/echo", echo);
+        let addr = start(pb);
+
+        let mut plain = ::std::net::TcpStream::connect(addr).unwrap();
+        plain.write_all(b"GET /echo HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+        let mut buf = vec!();
+        plain.read_to_end(&mut buf).unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 426 "));
+
+        let mut client = ::std::net::TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /echo HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                           Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        client.write_all(&masked(0x1, b"hello")).unwrap();
+        let head = read_head(&mut client);
+        assert!(head.starts_with("HTTP/1.1 101 "));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        let mut frame = [0; 7];
+        client.read_exact(&mut frame).unwrap();
+        assert_eq!(&frame, b"\x81\x05hello");
+        client.write_all(&masked(0x9, b"hi")).unwrap();
+        client.write_all(&masked(0x8, &[0x03, 0xe8])).unwrap();
+        buf.clear();
+        client.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"\x8a\x02hi\x88\x02\x03\xe8");
+    }
+}
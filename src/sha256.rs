@@ -0,0 +1,25 @@
+use hmac::{Hmac, Mac};
+
+use sha2::Sha256;
+
+///The HMAC-SHA256 of `data` signed with `key`
+pub fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn hmacs() {
+        assert_eq!(hex(&hmac(b"key", b"The quick brown fox jumps over the lazy dog")),
+                   "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8");
+    }
+}
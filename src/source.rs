@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+
+use hyper::StatusCode;
+
+use super::autoindex::{self, Entry};
+use super::content_type;
+use super::embed::EmbeddedBundle;
+use super::file_cache::{etag_for, FileCache, StaticFile};
+use super::pony::{ETag, SymlinkPolicy};
+use super::safe_path;
+
+///The settings of the mount a `StaticSource`
+/// is being read through
+pub struct ReadContext<'a> {
+    pub etag: ETag,
+    pub symlinks: SymlinkPolicy,
+    pub cache: Option<&'a Mutex<FileCache>>,
+}
+
+///Information about a path in a `StaticSource`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+///A place static files are served from.
+/// Paths are relative to the root of the source, `/` separated,
+/// without a leading `/` and have already had any `..` or
+/// dotfile segments rejected, the root itself is `""`.
+/// Errors with the kind `NotFound` become a 404,
+/// `PermissionDenied` becomes a 403
+pub trait StaticSource: Send + Sync {
+    ///Read a file, if `gzipped` is true the gzip encoded version
+    /// of the file should be returned or a `NotFound` error
+    fn open(&self, path: &str, gzipped: bool, cx: &ReadContext) -> io::Result<StaticFile>;
+    ///Information about a file or directory
+    fn metadata(&self, path: &str, cx: &ReadContext) -> io::Result<Metadata>;
+    ///The contents of a directory
+    fn list(&self, path: &str, cx: &ReadContext) -> io::Result<Vec<Entry>>;
+    ///Check that the source is usable, called when it is
+    /// added to a `PonyBuilder`
+    fn verify(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl<S: StaticSource + ?Sized> StaticSource for &'static S {
+    fn open(&self, path: &str, gzipped: bool, cx: &ReadContext) -> io::Result<StaticFile> {
+        (**self).open(path, gzipped, cx)
+    }
+    fn metadata(&self, path: &str, cx: &ReadContext) -> io::Result<Metadata> {
+        (**self).metadata(path, cx)
+    }
+    fn list(&self, path: &str, cx: &ReadContext) -> io::Result<Vec<Entry>> {
+        (**self).list(path, cx)
+    }
+    fn verify(&self) -> Result<(), String> {
+        (**self).verify()
+    }
+}
+
+///A directory on the local disk, reads go through the
+/// mount's symlink policy and file cache
+pub struct DiskSource {
+    root: PathBuf,
+}
+
+impl DiskSource {
+    pub fn new<P: AsRef<Path>>(root: P) -> DiskSource {
+        DiskSource {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+    ///The directory this source serves
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+    ///Join a relative path onto the root and verify the result
+    /// against the symlink policy
+    fn resolve(&self, path: &str, cx: &ReadContext) -> io::Result<PathBuf> {
+        let mut full = self.root.clone();
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            full.push(segment);
+        }
+        match safe_path::check(&self.root, &full, cx.symlinks) {
+            Ok(()) => Ok(full),
+            Err(StatusCode::Forbidden) => Err(io::Error::new(io::ErrorKind::PermissionDenied, "symbolic link not allowed")),
+            Err(_) => Err(not_found()),
+        }
+    }
+}
+
+impl StaticSource for DiskSource {
+    fn open(&self, path: &str, gzipped: bool, cx: &ReadContext) -> io::Result<StaticFile> {
+        let path = if gzipped {
+            format!("{}.gz", path)
+        } else {
+            path.to_string()
+        };
+        let full = self.resolve(&path, cx)?;
+        if !full.is_file() {
+            return Err(not_found())
+        }
+        match cx.cache {
+            Some(cache) => FileCache::get_shared(cache, &full, gzipped, cx.etag),
+            None => StaticFile::load(&full, gzipped, cx.etag),
+        }
+    }
+    fn metadata(&self, path: &str, cx: &ReadContext) -> io::Result<Metadata> {
+        let md = fs::metadata(self.resolve(path, cx)?)?;
+        Ok(Metadata {
+            is_dir: md.is_dir(),
+            len: md.len(),
+            modified: md.modified().ok(),
+        })
+    }
+    fn list(&self, path: &str, cx: &ReadContext) -> io::Result<Vec<Entry>> {
+        autoindex::read_entries(&self.resolve(path, cx)?, false)
+    }
+    fn verify(&self) -> Result<(), String> {
+        if !self.root.exists() {
+            return Err(format!("Static path does not exist\n{:?}", &self.root));
+        }
+        if self.root.is_file() {
+            return Err(format!("Static path must be a directory\n{:?}", &self.root));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct MemoryFile {
+    contents: Bytes,
+    gzip: Option<Bytes>,
+    modified: Option<SystemTime>,
+}
+
+///Files held in memory, directories are implied
+/// by the paths of the files inserted
+#[derive(Clone, Default)]
+pub struct MemorySource {
+    files: HashMap<String, MemoryFile>,
+}
+
+impl MemorySource {
+    pub fn new() -> MemorySource {
+        MemorySource::default()
+    }
+    ///Add or replace a file
+    pub fn insert<B: Into<Bytes>>(&mut self, path: &str, contents: B) -> &mut Self {
+        self.files.insert(path.trim_matches('/').to_string(), MemoryFile {
+            contents: contents.into(),
+            gzip: None,
+            modified: Some(SystemTime::now()),
+        });
+        self
+    }
+    ///Add a gzip encoded version of a file that
+    /// has already been inserted
+    pub fn insert_gzip<B: Into<Bytes>>(&mut self, path: &str, contents: B) -> &mut Self {
+        if let Some(file) = self.files.get_mut(path.trim_matches('/')) {
+            file.gzip = Some(contents.into());
+        }
+        self
+    }
+}
+
+impl StaticSource for MemorySource {
+    fn open(&self, path: &str, gzipped: bool, cx: &ReadContext) -> io::Result<StaticFile> {
+        let file = self.files.get(path).ok_or_else(not_found)?;
+        let contents = if gzipped {
+            file.gzip.clone().ok_or_else(not_found)?
+        } else {
+            file.contents.clone()
+        };
+        Ok(StaticFile {
+            etag: etag_for(cx.etag, &file.contents, file.modified),
+            content_type: content_type::for_path(Path::new(path), false),
+            contents,
+            modified: file.modified,
+        })
+    }
+    fn metadata(&self, path: &str, _cx: &ReadContext) -> io::Result<Metadata> {
+        if let Some(file) = self.files.get(path) {
+            return Ok(Metadata {
+                is_dir: false,
+                len: file.contents.len() as u64,
+                modified: file.modified,
+            })
+        }
+        let files = self.files.iter().map(|(p, f)| (p.as_str(), f.contents.len() as u64, f.modified));
+        if flat_is_dir(files, path) {
+            Ok(Metadata { is_dir: true, len: 0, modified: None })
+        } else {
+            Err(not_found())
+        }
+    }
+    fn list(&self, path: &str, _cx: &ReadContext) -> io::Result<Vec<Entry>> {
+        let files = self.files.iter().map(|(p, f)| (p.as_str(), f.contents.len() as u64, f.modified));
+        Ok(flat_list(files, path))
+    }
+}
+
+impl StaticSource for EmbeddedBundle {
+    fn open(&self, path: &str, gzipped: bool, cx: &ReadContext) -> io::Result<StaticFile> {
+        self.get(path)
+            .and_then(|file| file.to_static_file(gzipped, cx.etag))
+            .ok_or_else(not_found)
+    }
+    fn metadata(&self, path: &str, _cx: &ReadContext) -> io::Result<Metadata> {
+        if let Some(file) = self.get(path) {
+            return Ok(Metadata {
+                is_dir: false,
+                len: file.contents.len() as u64,
+                modified: Some(file.modified_time()),
+            })
+        }
+        if flat_is_dir(self.flat_files(), path) {
+            Ok(Metadata { is_dir: true, len: 0, modified: None })
+        } else {
+            Err(not_found())
+        }
+    }
+    fn list(&self, path: &str, _cx: &ReadContext) -> io::Result<Vec<Entry>> {
+        Ok(flat_list(self.flat_files(), path))
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "File not found")
+}
+
+fn dir_prefix(dir: &str) -> String {
+    let trimmed = dir.trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", trimmed)
+    }
+}
+
+///Check if any path in a flat list of files lives under `dir`
+pub fn flat_is_dir<'a, I>(files: I, dir: &str) -> bool
+    where I: Iterator<Item = (&'a str, u64, Option<SystemTime>)> {
+    let prefix = dir_prefix(dir);
+    let mut files = files;
+    files.any(|(path, _, _)| path.starts_with(&prefix))
+}
+
+///The files and directories directly inside of `dir`
+/// for a flat list of `(path, length, modified)`
+pub fn flat_list<'a, I>(files: I, dir: &str) -> Vec<Entry>
+    where I: Iterator<Item = (&'a str, u64, Option<SystemTime>)> {
+    let prefix = dir_prefix(dir);
+    let mut ret: Vec<Entry> = vec!();
+    for (path, len, modified) in files.filter(|&(path, _, _)| path.starts_with(&prefix)) {
+        let rest = &path[prefix.len()..];
+        let (name, is_dir) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], true),
+            None => (rest, false),
+        };
+        if ret.iter().any(|e| e.name == name) {
+            continue;
+        }
+        ret.push(Entry {
+            name: name.to_string(),
+            is_dir,
+            size: if is_dir { 0 } else { len },
+            modified,
+        });
+    }
+    autoindex::sort_entries(&mut ret);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cx() -> ReadContext<'static> {
+        ReadContext {
+            etag: ETag::Sha1,
+            symlinks: SymlinkPolicy::WithinRoot,
+            cache: None,
+        }
+    }
+
+    fn memory() -> MemorySource {
+        let mut source = MemorySource::new();
+        source.insert("index.html", "<h1>memory</h1>")
+            .insert("/css/site.css", "body {}")
+            .insert("js/app/main.js", "main()")
+            .insert_gzip("index.html", "gzipped");
+        source
+    }
+
+    #[test]
+    fn memory_open() {
+        let source = memory();
+        let file = source.open("index.html", false, &cx()).unwrap();
+        assert_eq!(&file.contents[..], b"<h1>memory</h1>");
+        assert_eq!(file.content_type, "text/html; charset=utf-8");
+        assert!(file.etag.is_some());
+        assert_eq!(&source.open("index.html", true, &cx()).unwrap().contents[..], b"gzipped");
+        let err = source.open("css/site.css", true, &cx()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(source.open("missing.html", false, &cx()).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn memory_metadata() {
+        let source = memory();
+        assert!(source.metadata("js/app", &cx()).unwrap().is_dir);
+        assert!(source.metadata("", &cx()).unwrap().is_dir);
+        let md = source.metadata("css/site.css", &cx()).unwrap();
+        assert!(!md.is_dir);
+        assert_eq!(md.len, 7);
+        assert!(source.metadata("js/ap", &cx()).is_err());
+    }
+
+    #[test]
+    fn memory_list() {
+        let names: Vec<(String, bool)> = memory().list("", &cx()).unwrap().into_iter().map(|e| (e.name, e.is_dir)).collect();
+        assert_eq!(names, vec![
+            (String::from("css"), true),
+            (String::from("js"), true),
+            (String::from("index.html"), false),
+        ]);
+    }
+
+    #[test]
+    fn disk() {
+        let source = DiskSource::new("examples/public");
+        assert!(source.verify().is_ok());
+        assert_eq!(&source.open("files/a.txt", false, &cx()).unwrap().contents[..], b"a\n");
+        assert!(source.open("index.html", true, &cx()).is_ok());
+        assert!(source.metadata("files", &cx()).unwrap().is_dir);
+        let err = source.open("links/outside.toml", false, &cx()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(DiskSource::new("junk/").verify().is_err());
+        assert!(DiskSource::new("examples/public/index.html").verify().is_err());
+    }
+
+    #[test]
+    fn embedded() {
+        let source = &::test_support::BUNDLE;
+        assert!(source.metadata("js/app", &cx()).unwrap().is_dir);
+        assert_eq!(source.list("js", &cx()).unwrap()[0].name, "app");
+        assert_eq!(&source.open("index.html", true, &cx()).unwrap().contents[..], b"gzipped");
+    }
+}
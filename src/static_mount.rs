@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use super::pony::{ETag, SymlinkPolicy};
+use super::embed::EmbeddedBundle;
+use super::source::{DiskSource, StaticSource};
+
+///A directory of static files served under a url prefix,
+/// each mount carries its own gzip, etag, caching
+/// and listing settings.
+/// A mount may be layered over several sources
+/// which are searched in order, the first source
+/// containing a file wins
+#[derive(Clone)]
+pub struct StaticMount {
+    pub prefix: String,
+    pub layers: Vec<Arc<dyn StaticSource>>,
+    pub gzip: bool,
+    pub etag: ETag,
+    pub max_age: Option<u32>,
+    pub autoindex: bool,
+    pub autoindex_hide_dotfiles: bool,
+    pub dotfiles: bool,
+    pub symlinks: SymlinkPolicy,
+    pub spa_fallback: bool,
+}
+
+impl StaticMount {
+    ///Create a new mount serving the directory at `path`
+    /// for any request starting with `prefix`
+    pub fn new(prefix: &str, path: &str) -> StaticMount {
+        Self::from_source(prefix, Arc::new(DiskSource::new(path)))
+    }
+    ///Create a new mount serving files embedded in the binary
+    /// for any request starting with `prefix`
+    pub fn embedded(prefix: &str, bundle: &'static EmbeddedBundle) -> StaticMount {
+        Self::from_source(prefix, Arc::new(bundle))
+    }
+    ///Create a new mount serving files from any `StaticSource`
+    /// for any request starting with `prefix`
+    pub fn from_source(prefix: &str, layer: Arc<dyn StaticSource>) -> StaticMount {
+        StaticMount {
+            prefix: normalize_prefix(prefix),
+            layers: vec![layer],
+            gzip: false,
+            etag: ETag::default(),
+            max_age: None,
+            autoindex: false,
+            autoindex_hide_dotfiles: false,
+            dotfiles: false,
+            symlinks: SymlinkPolicy::default(),
+            spa_fallback: false,
+        }
+    }
+    ///add a directory to search after any existing
+    ///directories for this mount
+    pub fn add_layer(mut self, path: &str) -> Self {
+        self.layers.push(Arc::new(DiskSource::new(path)));
+        self
+    }
+    ///add embedded files to search after any existing
+    ///layers for this mount
+    pub fn add_embedded_layer(mut self, bundle: &'static EmbeddedBundle) -> Self {
+        self.layers.push(Arc::new(bundle));
+        self
+    }
+    ///add a `StaticSource` to search after any existing
+    ///layers for this mount
+    pub fn add_source_layer(mut self, source: Arc<dyn StaticSource>) -> Self {
+        self.layers.push(source);
+        self
+    }
+    ///search for .gz files before the requested file
+    pub fn use_gzip(mut self) -> Self {
+        self.gzip = true;
+        self
+    }
+    ///sets the option for inserting an etag header
+    pub fn use_etag(mut self, etag: ETag) -> Self {
+        self.etag = etag;
+        self
+    }
+    ///add a `Cache-Control: public, max-age` header
+    ///to every file served from this mount
+    pub fn use_max_age(mut self, seconds: u32) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+    ///render a listing for directories that do not
+    ///contain an index.html
+    pub fn use_autoindex(mut self, hide_dotfiles: bool) -> Self {
+        self.autoindex = true;
+        self.autoindex_hide_dotfiles = hide_dotfiles;
+        self
+    }
+    ///serve files and directories whose name begins with a `.`
+    pub fn use_dotfiles(mut self) -> Self {
+        self.dotfiles = true;
+        self
+    }
+    ///sets the option for following symbolic links
+    pub fn use_symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+    ///serve this mount's index.html for any request that
+    ///is not found and has no file extension
+    pub fn use_spa_fallback(mut self) -> Self {
+        self.spa_fallback = true;
+        self
+    }
+    ///If the decoded request path falls under this mount
+    /// returns the remainder of the path, always starting with a `/`
+    pub fn strip(&self, path: &str) -> Option<String> {
+        if self.prefix == "/" {
+            return Some(path.to_string())
+        }
+        if !path.starts_with(&self.prefix) {
+            return None
+        }
+        let rest = &path[self.prefix.len()..];
+        if rest.is_empty() {
+            Some(String::from("/"))
+        } else if rest.starts_with('/') {
+            Some(rest.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+///Ensure a prefix starts with a `/` and does not end with one
+fn normalize_prefix(prefix: &str) -> String {
+    let trimmed = prefix.trim_matches('/');
+    format!("/{}", trimmed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix() {
+        assert_eq!(StaticMount::new("assets/", "dist").prefix, "/assets");
+        assert_eq!(StaticMount::new("/", "dist").prefix, "/");
+        assert_eq!(StaticMount::new("", "dist").prefix, "/");
+    }
+
+    #[test]
+    fn layers() {
+        let mount = StaticMount::new("/", "theme/custom")
+            .add_embedded_layer(&::test_support::BUNDLE)
+            .add_layer("theme/default")
+            .add_source_layer(Arc::new(::source::MemorySource::new()));
+        assert_eq!(mount.layers.len(), 4);
+        assert!(mount.layers[0].verify().is_err());
+    }
+
+    #[test]
+    fn strip() {
+        let mount = StaticMount::new("/assets", "dist");
+        assert_eq!(mount.strip("/assets"), Some(String::from("/")));
+        assert_eq!(mount.strip("/assets/"), Some(String::from("/")));
+        assert_eq!(mount.strip("/assets/app.js"), Some(String::from("/app.js")));
+        assert_eq!(mount.strip("/assetsx/app.js"), None);
+        assert_eq!(mount.strip("/app.js"), None);
+        let root = StaticMount::new("/", "dist");
+        assert_eq!(root.strip("/app.js"), Some(String::from("/app.js")));
+    }
+}
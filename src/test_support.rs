@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+use tar;
+use zip::ZipWriter;
+use zip::write::FileOptions;
+
+use super::embed::{EmbeddedBundle, EmbeddedFile};
+
+///A small site compiled in the way `embed::generate` would
+pub static BUNDLE: EmbeddedBundle = EmbeddedBundle {
+    files: &[
+        EmbeddedFile { path: "css/site.css", contents: b"body {}", gzip: None, sha1: "\"css\"", content_type: "text/css; charset=utf-8", modified: 1000 },
+        EmbeddedFile { path: "index.html", contents: b"<h1>embedded</h1>", gzip: Some(b"gzipped"), sha1: "\"index\"", content_type: "text/html; charset=utf-8", modified: 2000 },
+        EmbeddedFile { path: "js/app/main.js", contents: b"main()", gzip: None, sha1: "\"main\"", content_type: "application/javascript; charset=utf-8", modified: 3000 },
+    ],
+};
+
+///The files written to the archives made by `zip_file` and `tar_file`
+pub const ARCHIVE_FILES: &[(&str, &[u8])] = &[
+    ("./index.html", b"<h1>archive</h1>"),
+    ("index.html.gz", b"gzipped"),
+    ("css/site.css", b"body {}"),
+    ("js/app/main.js", b"main()"),
+];
+
+///A path in the temp directory that no other test process will use
+pub fn temp_path(name: &str) -> PathBuf {
+    ::std::env::temp_dir().join(format!("pony-{}-{}", ::std::process::id(), name))
+}
+
+///Write a file in the temp directory
+pub fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+    let path = temp_path(name);
+    File::create(&path).unwrap().write_all(contents).unwrap();
+    path
+}
+
+///Write a zip containing a small site
+pub fn zip_file(name: &str) -> PathBuf {
+    let path = temp_path(name);
+    let mut zip = ZipWriter::new(File::create(&path).unwrap());
+    zip.add_directory("css/", FileOptions::default()).unwrap();
+    for &(name, contents) in ARCHIVE_FILES {
+        zip.start_file(name, FileOptions::default()).unwrap();
+        zip.write_all(contents).unwrap();
+    }
+    zip.finish().unwrap();
+    path
+}
+
+///Write a tar containing a small site
+pub fn tar_file(name: &str) -> PathBuf {
+    let path = temp_path(name);
+    let mut tar = tar::Builder::new(File::create(&path).unwrap());
+    for &(name, contents) in ARCHIVE_FILES {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(1_500_000_000);
+        header.set_cksum();
+        tar.append_data(&mut header, name, contents).unwrap();
+    }
+    tar.finish().unwrap();
+    path
+}
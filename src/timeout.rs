@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use futures::Future;
+use futures::future::{ok, Either};
+
+use hyper::StatusCode;
+use hyper::server::Response;
+
+use tokio_timer::{self, Timer};
+
+use super::HyperResult;
+use super::scope::Scoped;
+
+///Time limits for route handlers, the timer runs on its
+/// own thread shared by every `Pony` a builder creates
+#[derive(Clone)]
+pub struct Timeouts {
+    timer: Timer,
+    ///The limit for each scope, `/` covers every route
+    pub limits: Scoped<Duration>,
+    ///The status sent when a handler runs out of time
+    pub status: StatusCode,
+}
+
+impl Timeouts {
+    pub fn new() -> Timeouts {
+        Timeouts {
+            timer: tokio_timer::wheel()
+                .tick_duration(Duration::from_millis(10))
+                .max_timeout(Duration::from_secs(24 * 60 * 60))
+                .channel_capacity(4096)
+                .thread_name("pony-timeouts")
+                .build(),
+            limits: Scoped::new(),
+            status: StatusCode::ServiceUnavailable,
+        }
+    }
+    ///Race a handler's response against a limit, if the limit
+    /// is reached first the handler is dropped and `on_timeout`
+    /// builds the response. If the timer can't take the limit
+    /// the handler runs without one
+    pub fn apply<F>(&self, fut: HyperResult, limit: Duration, on_timeout: F) -> HyperResult
+        where F: FnOnce() -> Response + 'static {
+        Box::new(
+            fut.select2(self.timer.sleep(limit))
+                .then(move |res| -> HyperResult {
+                    match res {
+                        Ok(Either::A((res, _))) => Box::new(ok(res)),
+                        Err(Either::A((e, _))) => Box::new(::futures::future::err(e)),
+                        Ok(Either::B(((), _))) => Box::new(ok(on_timeout())),
+                        Err(Either::B((_, fut))) => fut,
+                    }
+                })
+        )
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::empty;
+
+    #[test]
+    fn timed_out() {
+        let timeouts = Timeouts::new();
+        let res = timeouts.apply(Box::new(empty()), Duration::from_millis(20), || Response::new().with_status(StatusCode::GatewayTimeout))
+            .wait().unwrap();
+        assert_eq!(res.status(), StatusCode::GatewayTimeout);
+        let res = timeouts.apply(Box::new(ok(Response::new())), Duration::from_secs(5), || Response::new().with_status(StatusCode::GatewayTimeout))
+            .wait().unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+}
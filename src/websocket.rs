@@ -0,0 +1,488 @@
+use std::io;
+use std::str;
+
+use base64;
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+
+use hyper::{HttpVersion, Method, StatusCode};
+use hyper::server::Request;
+
+use sha1::{Sha1, Digest};
+
+use tokio_core::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+///Written frames are sent before more are queued past this size
+const WRITE_BUFFER: usize = 64 * 1024;
+
+///Called with the upgraded request and its socket, the future
+/// runs on the server's event loop until the handler is done
+pub type WebSocketHandler = fn(Request, WebSocket<TcpStream>) -> Box<dyn Future<Item = (), Error = ()>>;
+
+///A request that was answered with a 101, waiting for
+/// the server to hand its connection to the handler
+pub struct Upgrade {
+    pub handler: WebSocketHandler,
+    pub request: Request,
+}
+
+///A complete message, fragmented messages are joined
+/// before they are returned
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    ///Pings are answered with a pong automatically
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    ///A close status code and reason, a close from the
+    /// client is answered automatically
+    Close(Option<(u16, String)>),
+}
+
+impl Message {
+    fn opcode(&self) -> u8 {
+        match *self {
+            Message::Text(_) => 0x1,
+            Message::Binary(_) => 0x2,
+            Message::Close(_) => 0x8,
+            Message::Ping(_) => 0x9,
+            Message::Pong(_) => 0xA,
+        }
+    }
+}
+
+///Check a request's WebSocket handshake, returning the
+/// `Sec-WebSocket-Accept` value to send with a 101
+pub fn accept(req: &Request) -> Result<String, StatusCode> {
+    let header = |name: &str| req.headers().get_raw(name)
+        .and_then(|raw| raw.one())
+        .and_then(|line| str::from_utf8(line).ok());
+    let has_token = |name: &str, token: &str| header(name)
+        .map(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+        .unwrap_or(false);
+    if *req.method() != Method::Get || !has_token("Upgrade", "websocket") {
+        return Err(StatusCode::UpgradeRequired)
+    }
+    if header("Sec-WebSocket-Version") != Some("13") {
+        return Err(StatusCode::UpgradeRequired)
+    }
+    if req.version() != HttpVersion::Http11 || !has_token("Connection", "upgrade") {
+        return Err(StatusCode::BadRequest)
+    }
+    match header("Sec-WebSocket-Key") {
+        Some(key) if base64::decode(key.trim()).map(|k| k.len() == 16).unwrap_or(false) => Ok(accept_key(key.trim())),
+        _ => Err(StatusCode::BadRequest),
+    }
+}
+
+///If a handshake's `Origin` is one of `origins`, a request
+/// without one is refused since only browsers are checked
+pub fn origin_allowed(req: &Request, origins: &[String]) -> bool {
+    req.headers().get_raw("Origin")
+        .and_then(|raw| raw.one())
+        .and_then(|line| str::from_utf8(line).ok())
+        .map(|origin| origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin.trim())))
+        .unwrap_or(false)
+}
+
+///The `Sec-WebSocket-Accept` value for a `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    let mut sh = Sha1::default();
+    sh.input(key.as_bytes());
+    sh.input(GUID.as_bytes());
+    base64::encode(&sh.result())
+}
+
+///The server side of a WebSocket connection, a stream of
+/// the client's messages and a sink for replies
+pub struct WebSocket<T> {
+    io: T,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    ///The opcode and payload of a fragmented message
+    partial: Option<(u8, Vec<u8>)>,
+    max_message_size: usize,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<T: AsyncRead + AsyncWrite> WebSocket<T> {
+    ///Wrap an upgraded connection, `read` is anything already
+    /// read past the handshake
+    pub fn new(io: T, read: &[u8]) -> WebSocket<T> {
+        WebSocket {
+            io,
+            read_buf: read.to_vec(),
+            write_buf: vec!(),
+            partial: None,
+            max_message_size: 16 * 1024 * 1024,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+    ///Close the connection with a 1009 when a message is
+    /// larger than this, 16MiB by default
+    pub fn with_max_message_size(mut self, max: usize) -> Self {
+        self.max_message_size = max;
+        self
+    }
+
+    fn queue(&mut self, msg: &Message) {
+        let payload = match *msg {
+            Message::Text(ref text) => text.as_bytes().to_vec(),
+            Message::Binary(ref data) | Message::Ping(ref data) | Message::Pong(ref data) => data.clone(),
+            Message::Close(None) => vec!(),
+            Message::Close(Some((code, ref reason))) => {
+                let mut payload = code.to_be_bytes().to_vec();
+                payload.extend_from_slice(reason.as_bytes());
+                payload
+            },
+        };
+        if let Message::Close(_) = *msg {
+            self.close_sent = true;
+        }
+        self.write_buf.push(0x80 | msg.opcode());
+        match payload.len() {
+            len if len < 126 => self.write_buf.push(len as u8),
+            len if len <= 0xffff => {
+                self.write_buf.push(126);
+                self.write_buf.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                self.write_buf.push(127);
+                self.write_buf.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        self.write_buf.extend_from_slice(&payload);
+    }
+
+    ///Send what has been queued, `Ready` once it has all been written
+    fn flush(&mut self) -> Poll<(), io::Error> {
+        while !self.write_buf.is_empty() {
+            match self.io.write(&self.write_buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "websocket connection closed")),
+                Ok(written) => {
+                    self.write_buf.drain(..written);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            }
+        }
+        match self.io.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+
+    ///Send a close frame for a protocol error and fail the stream
+    fn fail(&mut self, code: u16, reason: &str) -> io::Error {
+        if !self.close_sent {
+            self.queue(&Message::Close(Some((code, reason.to_string()))));
+            let _ = self.flush();
+        }
+        self.close_received = true;
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+
+    ///Take the next complete frame off the read buffer as
+    /// its fin bit, opcode and unmasked payload
+    fn frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>, io::Error> {
+        if self.read_buf.len() < 2 {
+            return Ok(None)
+        }
+        let (first, second) = (self.read_buf[0], self.read_buf[1]);
+        if first & 0x70 != 0 {
+            return Err(self.fail(1002, "reserved bits are set"))
+        }
+        if second & 0x80 == 0 {
+            return Err(self.fail(1002, "client frames must be masked"))
+        }
+        let (len, mut offset) = match second & 0x7f {
+            126 if self.read_buf.len() >= 4 => (u16::from_be_bytes([self.read_buf[2], self.read_buf[3]]) as u64, 4),
+            127 if self.read_buf.len() >= 10 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&self.read_buf[2..10]);
+                (u64::from_be_bytes(bytes), 10)
+            },
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        let buffered = self.partial.as_ref().map(|(_, data)| data.len()).unwrap_or(0) as u64;
+        if len.saturating_add(buffered) > self.max_message_size as u64 {
+            return Err(self.fail(1009, "message too large"))
+        }
+        let len = len as usize;
+        if self.read_buf.len() < offset + 4 + len {
+            return Ok(None)
+        }
+        let mut mask = [0; 4];
+        mask.copy_from_slice(&self.read_buf[offset..offset + 4]);
+        offset += 4;
+        let payload = self.read_buf[offset..offset + len].iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+        self.read_buf.drain(..offset + len);
+        Ok(Some((first & 0x80 != 0, first & 0x0f, payload)))
+    }
+
+    ///Turn a frame into a message, `None` for the
+    /// start or middle of a fragmented one
+    fn message(&mut self, fin: bool, opcode: u8, payload: Vec<u8>) -> Result<Option<Message>, io::Error> {
+        match opcode {
+            0x8..=0xA if !fin || payload.len() > 125 => Err(self.fail(1002, "invalid control frame")),
+            0x8 => {
+                let close = match payload.len() {
+                    0 => None,
+                    1 => return Err(self.fail(1002, "invalid close frame")),
+                    _ => match String::from_utf8(payload[2..].to_vec()) {
+                        Ok(reason) => Some((u16::from_be_bytes([payload[0], payload[1]]), reason)),
+                        Err(_) => return Err(self.fail(1007, "close reason is not utf-8")),
+                    },
+                };
+                self.close_received = true;
+                if !self.close_sent {
+                    let code = close.as_ref().map(|(code, _)| (*code, String::new()));
+                    self.queue(&Message::Close(code));
+                    let _ = self.flush();
+                }
+                Ok(Some(Message::Close(close)))
+            },
+            0x9 => {
+                if !self.close_sent {
+                    self.queue(&Message::Pong(payload.clone()));
+                    let _ = self.flush();
+                }
+                Ok(Some(Message::Ping(payload)))
+            },
+            0xA => Ok(Some(Message::Pong(payload))),
+            0x0 => match self.partial.take() {
+                Some((opcode, mut data)) => {
+                    data.extend_from_slice(&payload);
+                    if fin {
+                        self.data(opcode, data).map(Some)
+                    } else {
+                        self.partial = Some((opcode, data));
+                        Ok(None)
+                    }
+                },
+                None => Err(self.fail(1002, "continuation without a message")),
+            },
+            0x1 | 0x2 if self.partial.is_some() => Err(self.fail(1002, "new message before the last finished")),
+            0x1 | 0x2 if !fin => {
+                self.partial = Some((opcode, payload));
+                Ok(None)
+            },
+            0x1 | 0x2 => self.data(opcode, payload).map(Some),
+            _ => Err(self.fail(1002, "unknown opcode")),
+        }
+    }
+
+    fn data(&mut self, opcode: u8, payload: Vec<u8>) -> Result<Message, io::Error> {
+        if opcode == 0x2 {
+            return Ok(Message::Binary(payload))
+        }
+        match String::from_utf8(payload) {
+            Ok(text) => Ok(Message::Text(text)),
+            Err(_) => Err(self.fail(1007, "text message is not utf-8")),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Stream for WebSocket<T> {
+    type Item = Message;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Option<Message>, io::Error> {
+        //keep replies to pings and closes moving
+        if !self.write_buf.is_empty() {
+            self.flush()?;
+        }
+        loop {
+            if self.close_received {
+                return Ok(Async::Ready(None))
+            }
+            if let Some((fin, opcode, payload)) = self.frame()? {
+                if let Some(msg) = self.message(fin, opcode, payload)? {
+                    return Ok(Async::Ready(Some(msg)))
+                }
+                continue;
+            }
+            let mut buf = [0; 8 * 1024];
+            match self.io.read(&mut buf) {
+                Ok(0) if self.read_buf.is_empty() && self.partial.is_none() => return Ok(Async::Ready(None)),
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "websocket closed mid message")),
+                Ok(read) => self.read_buf.extend_from_slice(&buf[..read]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Sink for WebSocket<T> {
+    type SinkItem = Message;
+    type SinkError = io::Error;
+    fn start_send(&mut self, msg: Message) -> StartSend<Message, io::Error> {
+        if self.close_sent {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "websocket is closing"))
+        }
+        if self.write_buf.len() >= WRITE_BUFFER {
+            self.flush()?;
+            if self.write_buf.len() >= WRITE_BUFFER {
+                return Ok(AsyncSink::NotReady(msg))
+            }
+        }
+        self.queue(&msg);
+        Ok(AsyncSink::Ready)
+    }
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.flush()
+    }
+    ///Send a normal close, unless one was already sent
+    fn close(&mut self) -> Poll<(), io::Error> {
+        if !self.close_sent {
+            self.queue(&Message::Close(Some((1000, String::new()))));
+        }
+        match self.flush()? {
+            Async::Ready(()) => self.io.shutdown(),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Uri;
+    use std::io::{Cursor, Read, Write};
+    use std::str::FromStr;
+
+    struct Mock {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Mock {}
+
+    impl AsyncWrite for Mock {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    ///A masked client frame
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn socket(input: Vec<u8>) -> WebSocket<Mock> {
+        WebSocket::new(Mock { input: Cursor::new(input), output: vec!() }, &[])
+    }
+
+    #[test]
+    fn handshake() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        let mut req = Request::new(Method::Get, Uri::from_str("/ws").unwrap());
+        assert_eq!(accept(&req), Err(StatusCode::UpgradeRequired));
+        req.headers_mut().set_raw("Upgrade", "WebSocket");
+        req.headers_mut().set_raw("Connection", "keep-alive, Upgrade");
+        req.headers_mut().set_raw("Sec-WebSocket-Version", "13");
+        req.headers_mut().set_raw("Sec-WebSocket-Key", "abc");
+        assert_eq!(accept(&req), Err(StatusCode::BadRequest));
+        req.headers_mut().set_raw("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(accept(&req), Ok(String::from("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")));
+        req.headers_mut().set_raw("Sec-WebSocket-Version", "8");
+        assert_eq!(accept(&req), Err(StatusCode::UpgradeRequired));
+    }
+
+    #[test]
+    fn origins() {
+        let origins = vec![String::from("https://example.com")];
+        let mut req = Request::new(Method::Get, Uri::from_str("/ws").unwrap());
+        assert!(!origin_allowed(&req, &origins));
+        req.headers_mut().set_raw("Origin", "https://Example.com");
+        assert!(origin_allowed(&req, &origins));
+        req.headers_mut().set_raw("Origin", "https://example.com.evil");
+        assert!(!origin_allowed(&req, &origins));
+    }
+
+    #[test]
+    fn messages() {
+        let mut input = frame(true, 0x1, "héllo".as_bytes());
+        input.extend(frame(false, 0x2, &[1, 2]));
+        input.extend(frame(true, 0x9, b"ping"));
+        input.extend(frame(false, 0x0, &[3]));
+        input.extend(frame(true, 0x0, &[4; 200]));
+        input.extend(frame(true, 0x8, &[0x03, 0xe8, b'b', b'y', b'e']));
+        let mut ws = socket(input);
+        let mut expected = vec![1, 2, 3];
+        expected.extend_from_slice(&[4; 200]);
+        assert_eq!(ws.by_ref().collect().wait().unwrap(), vec!(
+            Message::Text(String::from("héllo")),
+            Message::Ping(b"ping".to_vec()),
+            Message::Binary(expected),
+            Message::Close(Some((1000, String::from("bye")))),
+        ));
+        assert_eq!(ws.io.output, vec![0x8A, 4, b'p', b'i', b'n', b'g', 0x88, 2, 0x03, 0xe8]);
+        assert!(ws.start_send(Message::Text(String::from("late"))).is_err());
+    }
+
+    #[test]
+    fn send() {
+        let ws = socket(vec!());
+        let ws = ws.send(Message::Text(String::from("hi"))).wait().unwrap();
+        let ws = ws.send(Message::Binary(vec![0; 300])).wait().unwrap();
+        let mut ws = ws;
+        ws.close().unwrap();
+        let out = &ws.io.output;
+        assert_eq!(&out[..4], &[0x81, 2, b'h', b'i']);
+        assert_eq!(&out[4..8], &[0x82, 126, 1, 44]);
+        assert_eq!(&out[308..], &[0x88, 2, 0x03, 0xe8]);
+    }
+
+    #[test]
+    fn protocol_errors() {
+        let mut unmasked = frame(true, 0x1, b"hi");
+        unmasked[1] &= 0x7f;
+        let mut ws = socket(unmasked);
+        assert!(ws.by_ref().collect().wait().is_err());
+        assert_eq!(&ws.io.output[..4], &[0x88, 30, 0x03, 0xea]);
+        assert!(socket(frame(true, 0x0, b"hi")).collect().wait().is_err());
+        assert!(socket(frame(true, 0x1, &[0xff])).collect().wait().is_err());
+        assert!(socket(frame(false, 0x9, b"")).collect().wait().is_err());
+        assert!(socket(frame(true, 0x3, b"")).collect().wait().is_err());
+        assert!(socket(frame(false, 0x1, b"a")).collect().wait().is_err());
+        let big = socket(frame(true, 0x2, &[0; 200])).with_max_message_size(100);
+        assert!(big.collect().wait().is_err());
+    }
+}